@@ -250,7 +250,7 @@ async fn handle_inbound_datagram(udp_socket: UdpSocket, interface: String) {
     }
 }
 
-async fn new_tcp_stream<'a>(addr: SocketAddr, iface: &str) -> std::io::Result<TcpStream> {
+async fn new_tcp_stream(addr: SocketAddr, iface: &str) -> std::io::Result<TcpStream> {
     use socket2_ext::{AddressBinding, BindDeviceOption};
     let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
     socket.bind_to_device(BindDeviceOption::v4(iface))?;
@@ -0,0 +1,98 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::tcp::PendingTcpStream;
+
+/// Queue of the connections waiting for a listener, counting how many it
+/// hasn't taken yet.
+pub(crate) fn backlog() -> (TcpBacklogTx, TcpBacklogRx) {
+    let (stream_tx, stream_rx) = unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    (
+        TcpBacklogTx {
+            stream_tx,
+            queued: queued.clone(),
+        },
+        TcpBacklogRx { stream_rx },
+    )
+}
+
+/// The place of a connection in a listener's backlog, freed once taken by the
+/// listener or reset by the accept timeout, whichever comes first.
+pub(crate) struct TcpBacklogSlot {
+    queued: Arc<AtomicUsize>,
+    released: AtomicBool,
+}
+
+impl TcpBacklogSlot {
+    pub(crate) fn release(&self) {
+        if !self.released.swap(true, Ordering::AcqRel) {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+pub(crate) struct TcpBacklogTx {
+    stream_tx: UnboundedSender<PendingTcpStream>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl TcpBacklogTx {
+    /// Number of connections the listener hasn't taken yet.
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    /// Whether the listener is gone.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.stream_tx.is_closed()
+    }
+
+    /// Queue `stream`, it is dropped, resetting the connection, if the
+    /// listener is gone.
+    pub(crate) fn send(&self, mut stream: PendingTcpStream) -> Option<Arc<TcpBacklogSlot>> {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        let slot = Arc::new(TcpBacklogSlot {
+            queued: self.queued.clone(),
+            released: AtomicBool::new(false),
+        });
+        stream.set_backlog_slot(slot.clone());
+        match self.stream_tx.send(stream) {
+            Ok(()) => Some(slot),
+            Err(_) => {
+                slot.release();
+                None
+            }
+        }
+    }
+}
+
+pub(crate) struct TcpBacklogRx {
+    stream_rx: UnboundedReceiver<PendingTcpStream>,
+}
+
+impl TcpBacklogRx {
+    /// Take the next connection, skipping the ones that have waited longer
+    /// than the accept timeout, they have been reset already.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<PendingTcpStream>> {
+        loop {
+            let stream = std::task::ready!(self.stream_rx.poll_recv(cx));
+            let Some(mut stream) = stream else {
+                return Poll::Ready(None);
+            };
+            if let Some(slot) = stream.take_backlog_slot() {
+                slot.release();
+            }
+            if !stream.is_expired() {
+                return Poll::Ready(Some(stream));
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use smoltcp::wire::IpCidr;
+use spin::Mutex as SpinMutex;
+
+use crate::{
+    backlog::{TcpBacklogSlot, TcpBacklogTx},
+    limit::TcpLimiter,
+    tcp::{PendingTcpStream, TcpReject},
+};
+
+/// A listener bound to the destinations in `cidr`, on `port` if any.
+struct TcpBinding {
+    cidr: IpCidr,
+    port: Option<u16>,
+    stream_tx: TcpBacklogTx,
+}
+
+impl TcpBinding {
+    fn matches(&self, dst_addr: &SocketAddr) -> bool {
+        self.port.map_or(true, |port| port == dst_addr.port())
+            && self.cidr.contains_addr(&dst_addr.ip().into())
+    }
+
+    /// Whether both bindings get the same connections.
+    fn overlaps(&self, cidr: &IpCidr, port: Option<u16>) -> bool {
+        self.port == port
+            && self.cidr.prefix_len() == cidr.prefix_len()
+            && self.cidr.contains_addr(&cidr.address())
+    }
+
+    /// Bindings to a port win over any port, then longer prefixes win.
+    fn specificity(&self) -> (bool, u8) {
+        (self.port.is_some(), self.cidr.prefix_len())
+    }
+}
+
+/// Routes new connections to the listener bound to their destination.
+pub(crate) struct TcpBindings {
+    /// The listener returned by the builder, or its replacement, taking what no
+    /// binding matches until it is dropped.
+    default_tx: SpinMutex<TcpBacklogTx>,
+    bindings: SpinMutex<Vec<TcpBinding>>,
+    unmatched: TcpReject,
+    limiter: Arc<TcpLimiter>,
+}
+
+impl TcpBindings {
+    pub(crate) fn new(
+        default_tx: TcpBacklogTx,
+        unmatched: TcpReject,
+        limiter: Arc<TcpLimiter>,
+    ) -> Self {
+        Self {
+            default_tx: SpinMutex::new(default_tx),
+            bindings: SpinMutex::new(Vec::new()),
+            unmatched,
+            limiter,
+        }
+    }
+
+    /// Bind a listener, fails if a live one is bound to the same addresses.
+    pub(crate) fn bind(
+        &self,
+        cidr: IpCidr,
+        port: Option<u16>,
+        stream_tx: TcpBacklogTx,
+    ) -> std::io::Result<()> {
+        let mut bindings = self.bindings.lock();
+        // Forget the listeners dropped since.
+        bindings.retain(|binding| !binding.stream_tx.is_closed());
+        if bindings.iter().any(|binding| binding.overlaps(&cidr, port)) {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        bindings.push(TcpBinding {
+            cidr,
+            port,
+            stream_tx,
+        });
+        Ok(())
+    }
+
+    /// Replace the default listener, if the previous one has been dropped.
+    pub(crate) fn listen(&self, stream_tx: TcpBacklogTx) -> std::io::Result<()> {
+        let mut default_tx = self.default_tx.lock();
+        if !default_tx.is_closed() {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        *default_tx = stream_tx;
+        Ok(())
+    }
+
+    /// Hand `stream` to the most specific listener bound to its destination,
+    /// or to the default one. It is rejected if there is none left or if the
+    /// listener's backlog is full.
+    ///
+    /// Returns the slot taken in the listener's backlog.
+    pub(crate) fn dispatch(&self, stream: PendingTcpStream) -> Option<Arc<TcpBacklogSlot>> {
+        let dst_addr = *stream.remote_addr();
+        let bindings = self.bindings.lock();
+        let default_tx = self.default_tx.lock();
+        let stream_tx = bindings
+            .iter()
+            .filter(|binding| binding.matches(&dst_addr) && !binding.stream_tx.is_closed())
+            .max_by_key(|binding| binding.specificity())
+            .map_or(&*default_tx, |binding| &binding.stream_tx);
+
+        if stream_tx.is_closed() {
+            stream.reject(self.unmatched);
+            return None;
+        }
+        if let Err(reject) = self.limiter.admit_backlog(stream_tx.len()) {
+            stream.reject(reject);
+            return None;
+        }
+        stream_tx.send(stream)
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use smoltcp::storage::RingBuffer;
+
+use crate::memory::TcpMemory;
+
+/// An empty buffer is shrunk back to its minimum size after being idle this long.
+pub(crate) const BUFFER_SHRINK_IDLE: Duration = Duration::from_secs(10);
+
+/// A ring buffer starting at `min` bytes, grown up to `max` bytes as long as
+/// it keeps being filled up, and shrunk back once it stays empty.
+pub(crate) struct AdaptiveBuffer {
+    ring: RingBuffer<'static, u8>,
+    min: usize,
+    max: usize,
+    last_used: Instant,
+    memory: Arc<TcpMemory>,
+}
+
+impl AdaptiveBuffer {
+    pub(crate) fn new(min: usize, max: usize, memory: Arc<TcpMemory>) -> Self {
+        let min = min.min(max);
+        let size = memory.reserve(min, min);
+        Self {
+            ring: RingBuffer::new(vec![0u8; size]),
+            min,
+            max,
+            last_used: Instant::now(),
+            memory,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.ring.is_full()
+    }
+
+    pub(crate) fn enqueue_slice(&mut self, data: &[u8]) -> usize {
+        let n = self.ring.enqueue_slice(data);
+        if n > 0 {
+            self.last_used = Instant::now();
+        }
+        n
+    }
+
+    pub(crate) fn dequeue_slice(&mut self, data: &mut [u8]) -> usize {
+        let n = self.ring.dequeue_slice(data);
+        if n > 0 {
+            self.last_used = Instant::now();
+        }
+        n
+    }
+
+    /// Copy the buffered data into `data` without dequeuing it.
+    pub(crate) fn peek_slice(&mut self, data: &mut [u8]) -> usize {
+        self.ring.read_allocated(0, data)
+    }
+
+    /// The buffered data up to the end of the ring, without dequeuing it.
+    pub(crate) fn data(&self) -> &[u8] {
+        self.ring.get_allocated(0, self.ring.len())
+    }
+
+    /// Dequeue `amt` bytes returned by [`Self::data`].
+    pub(crate) fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.ring.len());
+        if amt > 0 {
+            self.ring.dequeue_allocated(amt);
+            self.last_used = Instant::now();
+        }
+    }
+
+    /// Drop the buffered data.
+    pub(crate) fn clear(&mut self) {
+        self.ring.clear();
+    }
+
+    /// Double the capacity within the ceiling and the memory budget.
+    ///
+    /// Returns `false` if the buffer couldn't grow.
+    pub(crate) fn grow(&mut self) -> bool {
+        let capacity = self.ring.capacity();
+        let wanted = capacity.max(self.min).saturating_mul(2).min(self.max);
+        let extra = self.memory.reserve(0, wanted.saturating_sub(capacity));
+        if extra == 0 {
+            return false;
+        }
+        self.resize(capacity + extra);
+        true
+    }
+
+    /// Shrink back to the minimum size if the buffer has been empty for a while.
+    pub(crate) fn shrink_if_idle(&mut self, now: Instant) {
+        let capacity = self.ring.capacity();
+        if capacity > self.min
+            && self.ring.is_empty()
+            && now.saturating_duration_since(self.last_used) >= BUFFER_SHRINK_IDLE
+        {
+            self.memory.release(capacity - self.min);
+            self.resize(self.min);
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let mut ring = RingBuffer::new(vec![0u8; size]);
+        while !self.ring.is_empty() {
+            let _ = ring.enqueue_slice(self.ring.dequeue_many(usize::MAX));
+        }
+        self.ring = ring;
+    }
+}
+
+impl Drop for AdaptiveBuffer {
+    fn drop(&mut self) {
+        self.memory.release(self.ring.capacity());
+    }
+}
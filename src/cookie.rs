@@ -0,0 +1,628 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use etherparse::{PacketBuilder, PacketBuilderStep, TcpHeader, TcpOptionElement};
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, TcpRepr, TcpSeqNumber,
+    },
+};
+use spin::Mutex as SpinMutex;
+
+use crate::packet::{AnyIpPktFrame, IpPacket};
+
+/// MSS values a cookie can encode, the same table Linux uses for IPv4.
+const COOKIE_MSS_TABLE: [u16; 4] = [536, 1300, 1440, 1460];
+/// A cookie is valid for one to two periods.
+const COOKIE_PERIOD_SECS: u64 = 64;
+const COOKIE_NO_WINDOW_SCALE: u32 = 0xF;
+const COOKIE_MAC_MASK: u32 = 0x00FF_FFFF;
+
+/// Connection key, (src, dst) of packets sent by the TUN side.
+type FlowTuple = (SocketAddr, SocketAddr);
+
+/// The handshake parameters recovered from a valid cookie.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SynCookie {
+    /// Initial sequence number of the client.
+    client_isn: u32,
+    /// Initial sequence number we answered with, i.e. the cookie.
+    cookie_isn: u32,
+    mss: u16,
+    window_scale: Option<u8>,
+}
+
+/// Stateless SYN-ACK generation and validation (RFC 4987 3.6).
+///
+/// The cookie carries, from the most significant bit: the period counter
+/// (2 bits), the MSS index (2 bits), the client's window scale (4 bits), and
+/// a MAC over the connection tuple and all of the above (24 bits).
+pub(crate) struct SynCookies {
+    secret: RandomState,
+    mtu: usize,
+    window: u16,
+    window_shift: u8,
+    translator: Arc<SeqTranslator>,
+}
+
+impl SynCookies {
+    pub(crate) fn new(mtu: usize, recv_buffer_size: usize, translator: Arc<SeqTranslator>) -> Self {
+        // The same shift smoltcp derives from its receive buffer, see `Socket::reset`.
+        let rx_cap_log2 = usize::BITS - recv_buffer_size.leading_zeros();
+        Self {
+            secret: RandomState::new(),
+            mtu,
+            window: recv_buffer_size.min(u16::MAX as usize) as u16,
+            window_shift: rx_cap_log2.saturating_sub(16).min(14) as u8,
+            translator,
+        }
+    }
+
+    pub(crate) fn translator(&self) -> &SeqTranslator {
+        &self.translator
+    }
+
+    /// Build the SYN-ACK answering the SYN in `frame`, with a cookie as our ISN.
+    pub(crate) fn syn_ack(&self, frame: &[u8]) -> Option<AnyIpPktFrame> {
+        let packet = IpPacket::new_checked(frame).ok()?;
+        let (src_ip, dst_ip) = (packet.src_addr(), packet.dst_addr());
+        let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+        let repr = TcpRepr::parse(
+            &segment,
+            &src_ip.into(),
+            &dst_ip.into(),
+            &ChecksumCapabilities::ignored(),
+        )
+        .ok()?;
+
+        let tuple = (
+            SocketAddr::new(src_ip, repr.src_port),
+            SocketAddr::new(dst_ip, repr.dst_port),
+        );
+        let client_isn = repr.seq_number.0 as u32;
+        let client_mss = repr.max_seg_size.unwrap_or(COOKIE_MSS_TABLE[0]);
+        let mss_index = COOKIE_MSS_TABLE
+            .iter()
+            .rposition(|&mss| mss <= client_mss)
+            .unwrap_or(0) as u32;
+        let window_scale = repr
+            .window_scale
+            .map_or(COOKIE_NO_WINDOW_SCALE, |scale| scale.min(14) as u32);
+        let cookie_isn = self.encode(&tuple, client_isn, period(), mss_index, window_scale);
+
+        let local_mss = match packet {
+            IpPacket::Ipv4(..) => self.mtu - 40,
+            IpPacket::Ipv6(..) => self.mtu - 60,
+        };
+        let mut options = vec![TcpOptionElement::MaximumSegmentSize(
+            local_mss.min(u16::MAX as usize) as u16,
+        )];
+        if repr.window_scale.is_some() {
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::WindowScale(self.window_shift));
+        }
+
+        let build = |builder: PacketBuilderStep<TcpHeader>| {
+            let builder = builder
+                .syn()
+                .ack(client_isn.wrapping_add(1))
+                .options(&options)
+                .ok()?;
+            let mut reply = Vec::with_capacity(builder.size(0));
+            builder.write(&mut reply, &[]).ok()?;
+            Some(reply)
+        };
+        match packet {
+            IpPacket::Ipv4(ref packet) => build(
+                PacketBuilder::ipv4(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                    .tcp(repr.dst_port, repr.src_port, cookie_isn, self.window),
+            ),
+            IpPacket::Ipv6(ref packet) => build(
+                PacketBuilder::ipv6(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                    .tcp(repr.dst_port, repr.src_port, cookie_isn, self.window),
+            ),
+        }
+    }
+
+    /// Validate the cookie acknowledged by the final ACK of the handshake in `frame`.
+    pub(crate) fn check(&self, frame: &[u8]) -> Option<SynCookie> {
+        self.check_at(frame, period())
+    }
+
+    fn check_at(&self, frame: &[u8], now: u64) -> Option<SynCookie> {
+        let packet = IpPacket::new_checked(frame).ok()?;
+        let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+        if !segment.ack() || segment.syn() || segment.rst() {
+            return None;
+        }
+
+        let tuple = (
+            SocketAddr::new(packet.src_addr(), segment.src_port()),
+            SocketAddr::new(packet.dst_addr(), segment.dst_port()),
+        );
+        let client_isn = (segment.seq_number().0 as u32).wrapping_sub(1);
+        let cookie_isn = (segment.ack_number().0 as u32).wrapping_sub(1);
+        let mss_index = (cookie_isn >> 28) & 0x3;
+        let window_scale = (cookie_isn >> 24) & 0xF;
+
+        let valid = [now, now.wrapping_sub(1)].into_iter().any(|period| {
+            self.encode(&tuple, client_isn, period, mss_index, window_scale) == cookie_isn
+        });
+        if !valid {
+            return None;
+        }
+
+        Some(SynCookie {
+            client_isn,
+            cookie_isn,
+            mss: COOKIE_MSS_TABLE[mss_index as usize],
+            window_scale: (window_scale != COOKIE_NO_WINDOW_SCALE).then_some(window_scale as u8),
+        })
+    }
+
+    /// Rebuild the client's SYN from its final ACK, to be replayed to a listening socket.
+    pub(crate) fn syn(&self, frame: &[u8], cookie: &SynCookie) -> Option<AnyIpPktFrame> {
+        let packet = IpPacket::new_checked(frame).ok()?;
+        let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+
+        let mut options = vec![TcpOptionElement::MaximumSegmentSize(cookie.mss)];
+        if let Some(window_scale) = cookie.window_scale {
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::WindowScale(window_scale));
+        }
+
+        let (src_port, dst_port) = (segment.src_port(), segment.dst_port());
+        let window = segment.window_len();
+        let build = |builder: PacketBuilderStep<TcpHeader>| {
+            let builder = builder.syn().options(&options).ok()?;
+            let mut syn = Vec::with_capacity(builder.size(0));
+            builder.write(&mut syn, &[]).ok()?;
+            Some(syn)
+        };
+        match packet {
+            IpPacket::Ipv4(ref packet) => build(
+                PacketBuilder::ipv4(packet.src_addr().octets(), packet.dst_addr().octets(), 64)
+                    .tcp(src_port, dst_port, cookie.client_isn, window),
+            ),
+            IpPacket::Ipv6(ref packet) => build(
+                PacketBuilder::ipv6(packet.src_addr().octets(), packet.dst_addr().octets(), 64)
+                    .tcp(src_port, dst_port, cookie.client_isn, window),
+            ),
+        }
+    }
+
+    /// The window shift advertised in our SYN-ACK for a cookie.
+    pub(crate) fn window_shift(&self, cookie: &SynCookie) -> u8 {
+        if cookie.window_scale.is_some() {
+            self.window_shift
+        } else {
+            0
+        }
+    }
+
+    fn encode(
+        &self,
+        tuple: &FlowTuple,
+        client_isn: u32,
+        period: u64,
+        mss_index: u32,
+        window_scale: u32,
+    ) -> u32 {
+        let mac =
+            self.secret
+                .hash_one((tuple, client_isn, period, mss_index, window_scale)) as u32;
+        ((period as u32 & 0x3) << 30)
+            | (mss_index << 28)
+            | (window_scale << 24)
+            | (mac & COOKIE_MAC_MASK)
+    }
+}
+
+fn period() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / COOKIE_PERIOD_SECS
+}
+
+struct TranslatedFlow {
+    cookie_isn: u32,
+    /// Window shift the client uses, as advertised in our cookie SYN-ACK.
+    advertised_shift: u8,
+    /// Window shift of the socket, learned from its SYN-ACK.
+    socket_shift: u8,
+    /// Socket's ISN minus the cookie ISN, unknown until the socket's SYN-ACK.
+    delta: Option<u32>,
+    held: Vec<AnyIpPktFrame>,
+}
+
+#[derive(Default)]
+struct SeqTranslatorInner {
+    flows: HashMap<FlowTuple, TranslatedFlow>,
+    ready: VecDeque<AnyIpPktFrame>,
+}
+
+/// Translates sequence numbers between the cookie ISN known by the client and
+/// the random ISN chosen by the smoltcp socket, which cannot be set.
+///
+/// It sits between the interface and the device: the socket's own SYN-ACK is
+/// swallowed, and the client's packets are held until it has been seen.
+pub(crate) struct SeqTranslator {
+    inner: SpinMutex<SeqTranslatorInner>,
+    ready_avail: Arc<AtomicBool>,
+}
+
+impl SeqTranslator {
+    pub(crate) fn new(ready_avail: Arc<AtomicBool>) -> Self {
+        Self {
+            inner: SpinMutex::new(SeqTranslatorInner::default()),
+            ready_avail,
+        }
+    }
+
+    /// Start translating the flow established with `cookie`, `held` is the
+    /// client's final ACK replayed once the socket has answered the SYN.
+    pub(crate) fn register(
+        &self,
+        tuple: FlowTuple,
+        cookie: &SynCookie,
+        advertised_shift: u8,
+        held: AnyIpPktFrame,
+    ) {
+        let flow = TranslatedFlow {
+            cookie_isn: cookie.cookie_isn,
+            advertised_shift,
+            socket_shift: 0,
+            delta: None,
+            held: vec![held],
+        };
+        self.inner.lock().flows.insert(tuple, flow);
+    }
+
+    pub(crate) fn remove(&self, tuple: &FlowTuple) {
+        self.inner.lock().flows.remove(tuple);
+    }
+
+    /// Frames released after the socket's SYN-ACK, to be received before anything else.
+    pub(crate) fn take_ready(&self) -> Option<AnyIpPktFrame> {
+        self.inner.lock().ready.pop_front()
+    }
+
+    /// The socket's sequence number of `seq_number` as sent to the client.
+    pub(crate) fn socket_seq(&self, tuple: &FlowTuple, seq_number: u32) -> u32 {
+        let inner = self.inner.lock();
+        match inner.flows.get(tuple).and_then(|flow| flow.delta) {
+            Some(delta) => seq_number.wrapping_add(delta),
+            None => seq_number,
+        }
+    }
+
+    /// Translate a frame received from the client, `None` if it is held.
+    pub(crate) fn ingress(&self, mut frame: AnyIpPktFrame) -> Option<AnyIpPktFrame> {
+        let Some((tuple, syn)) = tcp_flow(&frame) else {
+            return Some(frame);
+        };
+
+        let mut inner = self.inner.lock();
+        let Some(flow) = inner.flows.get_mut(&tuple) else {
+            return Some(frame);
+        };
+        match flow.delta {
+            Some(delta) => {
+                translate_ingress(&mut frame, delta);
+                Some(frame)
+            }
+            // The replayed SYN goes through untouched.
+            None if syn => Some(frame),
+            None => {
+                flow.held.push(frame);
+                None
+            }
+        }
+    }
+
+    /// Translate a frame sent to the client, `false` if it must be dropped.
+    pub(crate) fn egress(&self, frame: &mut [u8]) -> bool {
+        let Some(((dst_addr, src_addr), _)) = tcp_flow(frame) else {
+            return true;
+        };
+        let tuple = (src_addr, dst_addr);
+
+        let mut inner = self.inner.lock();
+        let SeqTranslatorInner { flows, ready } = &mut *inner;
+        let Some(flow) = flows.get_mut(&tuple) else {
+            return true;
+        };
+
+        match flow.delta {
+            Some(delta) => {
+                let (advertised_shift, socket_shift) = (flow.advertised_shift, flow.socket_shift);
+                with_tcp_packet(frame, |segment| {
+                    let seq_number = segment.seq_number().0 as u32;
+                    segment.set_seq_number(TcpSeqNumber(seq_number.wrapping_sub(delta) as i32));
+                    if advertised_shift != socket_shift {
+                        let window = (segment.window_len() as u32) << socket_shift;
+                        let window = (window >> advertised_shift).min(u16::MAX as u32);
+                        segment.set_window_len(window as u16);
+                    }
+                });
+                true
+            }
+            None => {
+                // Only the socket's SYN-ACK is expected, it tells the socket's ISN.
+                let syn_ack = with_tcp_packet(frame, |segment| {
+                    (segment.syn() && segment.ack()).then(|| {
+                        let window_scale = TcpRepr::parse(
+                            &TcpPacket::new_unchecked(segment.as_ref()),
+                            &IpAddress::from(dst_addr.ip()),
+                            &IpAddress::from(src_addr.ip()),
+                            &ChecksumCapabilities::ignored(),
+                        )
+                        .ok()
+                        .and_then(|repr| repr.window_scale);
+                        (segment.seq_number().0 as u32, window_scale)
+                    })
+                });
+                if let Some(Some((socket_isn, window_scale))) = syn_ack {
+                    let delta = socket_isn.wrapping_sub(flow.cookie_isn);
+                    flow.delta = Some(delta);
+                    flow.socket_shift = window_scale.unwrap_or(0);
+                    for mut held in flow.held.drain(..) {
+                        translate_ingress(&mut held, delta);
+                        ready.push_back(held);
+                    }
+                    self.ready_avail.store(true, Ordering::Release);
+                }
+                false
+            }
+        }
+    }
+}
+
+fn translate_ingress(frame: &mut [u8], delta: u32) {
+    with_tcp_packet(frame, |segment| {
+        if segment.ack() {
+            let ack_number = segment.ack_number().0 as u32;
+            segment.set_ack_number(TcpSeqNumber(ack_number.wrapping_add(delta) as i32));
+        }
+    });
+}
+
+/// The (src, dst) tuple of a TCP frame, and whether it is a SYN without ACK.
+fn tcp_flow(frame: &[u8]) -> Option<(FlowTuple, bool)> {
+    let packet = IpPacket::new_checked(frame).ok()?;
+    if packet.protocol() != IpProtocol::Tcp {
+        return None;
+    }
+    let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+    let tuple = (
+        SocketAddr::new(packet.src_addr(), segment.src_port()),
+        SocketAddr::new(packet.dst_addr(), segment.dst_port()),
+    );
+    Some((tuple, segment.syn() && !segment.ack()))
+}
+
+/// Apply `f` to the TCP segment in `frame` and recompute its checksum.
+fn with_tcp_packet<R>(
+    frame: &mut [u8],
+    f: impl FnOnce(&mut TcpPacket<&mut [u8]>) -> R,
+) -> Option<R> {
+    let (src_addr, dst_addr, range) = match IpVersion::of_packet(frame).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(&*frame).ok()?;
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            let range = packet.header_len() as usize..packet.total_len() as usize;
+            (
+                IpAddress::Ipv4(packet.src_addr()),
+                IpAddress::Ipv4(packet.dst_addr()),
+                range,
+            )
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(&*frame).ok()?;
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            let range = packet.header_len()..packet.header_len() + packet.payload_len() as usize;
+            (
+                IpAddress::Ipv6(packet.src_addr()),
+                IpAddress::Ipv6(packet.dst_addr()),
+                range,
+            )
+        }
+    };
+
+    let mut segment = TcpPacket::new_checked(frame.get_mut(range)?).ok()?;
+    let result = f(&mut segment);
+    segment.fill_checksum(&src_addr, &dst_addr);
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [10, 0, 0, 2];
+    const SERVER: [u8; 4] = [1, 2, 3, 4];
+
+    fn tuple() -> FlowTuple {
+        (
+            "10.0.0.2:40000".parse().unwrap(),
+            "1.2.3.4:80".parse().unwrap(),
+        )
+    }
+
+    fn frame(
+        (src, dst): ([u8; 4], [u8; 4]),
+        (src_port, dst_port): (u16, u16),
+        seq_number: u32,
+        build: impl FnOnce(PacketBuilderStep<TcpHeader>) -> PacketBuilderStep<TcpHeader>,
+    ) -> AnyIpPktFrame {
+        let builder =
+            build(PacketBuilder::ipv4(src, dst, 64).tcp(src_port, dst_port, seq_number, 100));
+        let mut frame = Vec::with_capacity(builder.size(0));
+        builder.write(&mut frame, &[]).unwrap();
+        frame
+    }
+
+    /// The client's final ACK of a handshake answered with `cookie_isn`.
+    fn final_ack(client_isn: u32, cookie_isn: u32) -> AnyIpPktFrame {
+        frame(
+            (CLIENT, SERVER),
+            (40000, 80),
+            client_isn.wrapping_add(1),
+            |builder| builder.ack(cookie_isn.wrapping_add(1)),
+        )
+    }
+
+    fn seq_ack(frame: &[u8]) -> (u32, u32, u16) {
+        let packet = Ipv4Packet::new_checked(frame).unwrap();
+        let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+        (
+            segment.seq_number().0 as u32,
+            segment.ack_number().0 as u32,
+            segment.window_len(),
+        )
+    }
+
+    fn syn_cookies() -> SynCookies {
+        let translator = Arc::new(SeqTranslator::new(Arc::new(AtomicBool::new(false))));
+        SynCookies::new(1500, 0x10000, translator)
+    }
+
+    #[test]
+    fn cookie_valid_for_two_periods() {
+        let syn_cookies = syn_cookies();
+        // Including the rollover of the 2 period bits.
+        for period in [0, 3, 64, u64::MAX - 1] {
+            let cookie_isn = syn_cookies.encode(&tuple(), 1000, period, 2, 7);
+            let ack = final_ack(1000, cookie_isn);
+
+            let cookie = syn_cookies.check_at(&ack, period).unwrap();
+            assert_eq!(cookie.client_isn, 1000);
+            assert_eq!(cookie.cookie_isn, cookie_isn);
+            assert_eq!(cookie.mss, COOKIE_MSS_TABLE[2]);
+            assert_eq!(cookie.window_scale, Some(7));
+
+            let next = period.wrapping_add(1);
+            assert!(syn_cookies.check_at(&ack, next).is_some());
+            assert!(syn_cookies.check_at(&ack, next.wrapping_add(1)).is_none());
+        }
+    }
+
+    #[test]
+    fn cookie_bound_to_connection() {
+        let syn_cookies = syn_cookies();
+        let cookie_isn = syn_cookies.encode(&tuple(), 1000, 5, 0, COOKIE_NO_WINDOW_SCALE);
+        assert!(syn_cookies
+            .check_at(&final_ack(1000, cookie_isn), 5)
+            .is_some_and(|cookie| cookie.window_scale.is_none()));
+
+        // Another client ISN, or a forged cookie.
+        assert!(syn_cookies
+            .check_at(&final_ack(1001, cookie_isn), 5)
+            .is_none());
+        assert!(syn_cookies
+            .check_at(&final_ack(1000, cookie_isn ^ 1), 5)
+            .is_none());
+        // Another port.
+        let ack = frame((CLIENT, SERVER), (40001, 80), 1001, |builder| {
+            builder.ack(cookie_isn.wrapping_add(1))
+        });
+        assert!(syn_cookies.check_at(&ack, 5).is_none());
+    }
+
+    #[test]
+    fn syn_ack_answered_cookie() {
+        let syn_cookies = syn_cookies();
+        let syn = frame((CLIENT, SERVER), (40000, 80), 1000, |builder| {
+            builder
+                .syn()
+                .options(&[
+                    TcpOptionElement::MaximumSegmentSize(1400),
+                    TcpOptionElement::Noop,
+                    TcpOptionElement::WindowScale(7),
+                ])
+                .unwrap()
+        });
+
+        let syn_ack = syn_cookies.syn_ack(&syn).unwrap();
+        let (cookie_isn, ack_number, _) = seq_ack(&syn_ack);
+        assert_eq!(ack_number, 1001);
+
+        let cookie = syn_cookies.check(&final_ack(1000, cookie_isn)).unwrap();
+        // Rounded down to the table.
+        assert_eq!(cookie.mss, 1300);
+        assert_eq!(cookie.window_scale, Some(7));
+        assert_eq!(syn_cookies.window_shift(&cookie), 1);
+    }
+
+    #[test]
+    fn translator_shifts_sequence_numbers() {
+        let ready_avail = Arc::new(AtomicBool::new(false));
+        let translator = SeqTranslator::new(ready_avail.clone());
+        let cookie = SynCookie {
+            client_isn: 1000,
+            cookie_isn: 5000,
+            mss: 1460,
+            window_scale: Some(7),
+        };
+        translator.register(tuple(), &cookie, 2, final_ack(1000, 5000));
+
+        // Held until the socket has answered the replayed SYN.
+        let data = frame((CLIENT, SERVER), (40000, 80), 1001, |builder| {
+            builder.ack(5001)
+        });
+        assert!(translator.ingress(data).is_none());
+
+        let mut syn_ack = frame((SERVER, CLIENT), (80, 40000), 90000, |builder| {
+            builder
+                .syn()
+                .ack(1001)
+                .options(&[TcpOptionElement::Noop, TcpOptionElement::WindowScale(7)])
+                .unwrap()
+        });
+        assert!(!translator.egress(&mut syn_ack));
+        assert!(ready_avail.load(Ordering::Acquire));
+        assert_eq!(seq_ack(&translator.take_ready().unwrap()).1, 90001);
+        assert_eq!(seq_ack(&translator.take_ready().unwrap()).1, 90001);
+        assert!(translator.take_ready().is_none());
+
+        let ack = frame((CLIENT, SERVER), (40000, 80), 1001, |builder| {
+            builder.ack(5101)
+        });
+        assert_eq!(seq_ack(&translator.ingress(ack).unwrap()).1, 90101);
+        assert_eq!(translator.socket_seq(&tuple(), 5101), 90101);
+
+        // Back to the cookie ISN, and the window to the shift the client knows.
+        let mut data = frame((SERVER, CLIENT), (80, 40000), 90101, |builder| {
+            builder.ack(1001)
+        });
+        assert!(translator.egress(&mut data));
+        assert_eq!(seq_ack(&data), (5101, 1001, (100 << 7) >> 2));
+        let packet = Ipv4Packet::new_checked(&data[..]).unwrap();
+        let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+        assert!(segment.verify_checksum(
+            &IpAddress::Ipv4(packet.src_addr()),
+            &IpAddress::Ipv4(packet.dst_addr())
+        ));
+
+        // Untouched once removed.
+        translator.remove(&tuple());
+        let ack = frame((CLIENT, SERVER), (40000, 80), 1001, |builder| {
+            builder.ack(5101)
+        });
+        assert_eq!(seq_ack(&translator.ingress(ack).unwrap()).1, 5101);
+    }
+}
@@ -7,32 +7,82 @@ use smoltcp::{
     phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
     time::Instant,
 };
-use tokio::sync::mpsc::{unbounded_channel, Permit, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{
+    unbounded_channel, PermitIterator, Sender, UnboundedReceiver, UnboundedSender,
+};
+
+use crate::{cookie::SeqTranslator, info::TcpFlowMonitor, packet::AnyIpPktFrame, pmtu};
 
-use crate::packet::AnyIpPktFrame;
+pub(super) const DEFAULT_MTU: usize = 1504;
 
 pub(super) struct VirtualDevice {
     in_buf_avail: Arc<AtomicBool>,
     in_buf: UnboundedReceiver<Vec<u8>>,
     out_buf: Sender<AnyIpPktFrame>,
+    translator: Option<Arc<SeqTranslator>>,
+    monitor: Arc<TcpFlowMonitor>,
+    mtu: usize,
+    /// Permits reserved for a frame that may be split, one per segment.
+    max_permits: usize,
 }
 
 impl VirtualDevice {
     pub(super) fn new(
         iface_egress_tx: Sender<AnyIpPktFrame>,
+        mtu: usize,
     ) -> (Self, UnboundedSender<Vec<u8>>, Arc<AtomicBool>) {
         let iface_ingress_tx_avail = Arc::new(AtomicBool::new(false));
         let (iface_ingress_tx, iface_ingress_rx) = unbounded_channel();
+        let max_permits = pmtu::max_segments(mtu).min(iface_egress_tx.max_capacity());
         (
             Self {
                 in_buf_avail: iface_ingress_tx_avail.clone(),
                 in_buf: iface_ingress_rx,
                 out_buf: iface_egress_tx,
+                translator: None,
+                monitor: Arc::default(),
+                mtu,
+                max_permits,
             },
             iface_ingress_tx,
             iface_ingress_tx_avail,
         )
     }
+
+    /// Translate TCP sequence numbers of SYN cookie connections on both directions.
+    pub(super) fn set_translator(&mut self, translator: Arc<SeqTranslator>) {
+        self.translator = Some(translator);
+    }
+
+    /// Statistics of the connections, gathered from the packets going through.
+    pub(super) fn monitor(&self) -> &Arc<TcpFlowMonitor> {
+        &self.monitor
+    }
+
+    /// Permits to reserve for a frame, a single one unless a connection has
+    /// its path MTU lowered and may have its segments split.
+    fn permits(&self) -> usize {
+        if self.monitor.path_mtu_lowered() {
+            self.max_permits
+        } else {
+            1
+        }
+    }
+
+    fn recv_buf(&mut self) -> Option<Vec<u8>> {
+        let Some(ref translator) = self.translator else {
+            return self.in_buf.try_recv().ok();
+        };
+        loop {
+            let buffer = match translator.take_ready() {
+                Some(buffer) => buffer,
+                None => self.in_buf.try_recv().ok()?,
+            };
+            if let Some(buffer) = translator.ingress(buffer) {
+                return Some(buffer);
+            }
+        }
+    }
 }
 
 impl Device for VirtualDevice {
@@ -40,22 +90,39 @@ impl Device for VirtualDevice {
     type TxToken<'a> = VirtualTxToken<'a>;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        let Ok(buffer) = self.in_buf.try_recv() else {
+        let Some(buffer) = self.recv_buf() else {
             self.in_buf_avail.store(false, Ordering::Release);
             return None;
         };
 
-        let Ok(permit) = self.out_buf.try_reserve() else {
+        let Ok(permits) = self.out_buf.try_reserve_many(self.permits()) else {
             self.in_buf_avail.store(false, Ordering::Release);
             return None;
         };
 
-        Some((Self::RxToken { buffer }, Self::TxToken { permit }))
+        self.monitor.ingress(&buffer);
+
+        let translator = self.translator.as_deref();
+        let monitor = &self.monitor;
+        Some((
+            Self::RxToken { buffer },
+            Self::TxToken {
+                permits,
+                translator,
+                monitor,
+            },
+        ))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        match self.out_buf.try_reserve() {
-            Ok(permit) => Some(Self::TxToken { permit }),
+        let translator = self.translator.as_deref();
+        let monitor = &self.monitor;
+        match self.out_buf.try_reserve_many(self.permits()) {
+            Ok(permits) => Some(Self::TxToken {
+                permits,
+                translator,
+                monitor,
+            }),
             Err(_) => None,
         }
     }
@@ -63,7 +130,7 @@ impl Device for VirtualDevice {
     fn capabilities(&self) -> DeviceCapabilities {
         let mut capabilities = DeviceCapabilities::default();
         capabilities.medium = Medium::Ip;
-        capabilities.max_transmission_unit = 1504;
+        capabilities.max_transmission_unit = self.mtu;
         capabilities
     }
 }
@@ -82,17 +149,35 @@ impl RxToken for VirtualRxToken {
 }
 
 pub(super) struct VirtualTxToken<'a> {
-    permit: Permit<'a, Vec<u8>>,
+    permits: PermitIterator<'a, Vec<u8>>,
+    translator: Option<&'a SeqTranslator>,
+    monitor: &'a TcpFlowMonitor,
 }
 
 impl<'a> TxToken for VirtualTxToken<'a> {
-    fn consume<R, F>(self, len: usize, f: F) -> R
+    fn consume<R, F>(mut self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
         let mut buffer = vec![0u8; len];
         let result = f(&mut buffer);
-        self.permit.send(buffer);
+        let path_mtu = self.monitor.egress(&buffer);
+        if !self
+            .translator
+            .map_or(true, |translator| translator.egress(&mut buffer))
+        {
+            return result;
+        }
+
+        let segments = match path_mtu.filter(|&path_mtu| buffer.len() > path_mtu) {
+            Some(path_mtu) => pmtu::segment(buffer, path_mtu),
+            None => vec![buffer],
+        };
+        // Only a channel smaller than the segment count runs out of permits,
+        // what is left is retransmitted like any lost segment.
+        for (segment, permit) in segments.into_iter().zip(&mut self.permits) {
+            permit.send(segment);
+        }
         result
     }
 }
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use smoltcp::{
+    socket::tcp::State as TcpState,
+    wire::{IpProtocol, TcpOption, TcpPacket},
+};
+use spin::Mutex as SpinMutex;
+
+use crate::packet::IpPacket;
+
+/// Snapshot of a TCP connection, returned by [`TcpStream::info`](crate::TcpStream::info).
+#[derive(Debug, Clone, Copy)]
+pub struct TcpInfo {
+    /// State of the smoltcp socket, see [`TcpState`](crate::TcpState).
+    pub state: TcpState,
+    /// Payload bytes sent to the peer, retransmissions excluded.
+    pub bytes_sent: u64,
+    /// Payload bytes received from the peer, retransmissions excluded.
+    pub bytes_received: u64,
+    /// Bytes written but not acknowledged by the peer yet.
+    pub send_queue: usize,
+    /// Bytes received but not read yet.
+    pub recv_queue: usize,
+    /// Window last advertised by the peer.
+    pub send_window: usize,
+    /// Window last advertised to the peer.
+    pub recv_window: usize,
+    /// Smoothed round-trip time, `None` until a segment has been acknowledged.
+    pub rtt: Option<Duration>,
+    /// Number of segments sent again.
+    pub retransmissions: u64,
+    /// Time since the connection was accepted.
+    pub age: Duration,
+}
+
+/// The client's SYN of an accepted connection, returned by
+/// [`TcpStream::syn_info`](crate::TcpStream::syn_info).
+#[derive(Debug, Clone)]
+pub struct TcpSynInfo {
+    /// TTL (IPv4) or hop limit (IPv6).
+    pub hop_limit: u8,
+    /// TOS (IPv4) or traffic class (IPv6), the DSCP is the upper 6 bits.
+    pub tos: u8,
+    /// Flow label, IPv6 only.
+    pub flow_label: Option<u32>,
+    /// Window, unscaled.
+    pub window: u16,
+    /// Maximum segment size option, before it is clamped to the MTU.
+    pub mss: Option<u16>,
+    /// Window scale option.
+    pub window_scale: Option<u8>,
+    /// Whether the SACK-permitted option is present.
+    pub sack_permitted: bool,
+    /// Timestamps option, as (TSval, TSecr).
+    pub timestamps: Option<(u32, u32)>,
+    /// The options as sent, in order.
+    pub options: Vec<u8>,
+}
+
+impl TcpSynInfo {
+    /// Parse the SYN in `frame`, `None` for any other segment.
+    pub(crate) fn parse(frame: &[u8]) -> Option<Self> {
+        let packet = IpPacket::new_checked(frame).ok()?;
+        if packet.protocol() != IpProtocol::Tcp {
+            return None;
+        }
+        let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+        if !segment.syn() || segment.ack() {
+            return None;
+        }
+
+        let (hop_limit, tos, flow_label) = match packet {
+            IpPacket::Ipv4(ref packet) => {
+                (packet.hop_limit(), packet.dscp() << 2 | packet.ecn(), None)
+            }
+            IpPacket::Ipv6(ref packet) => (
+                packet.hop_limit(),
+                packet.traffic_class(),
+                Some(packet.flow_label()),
+            ),
+        };
+
+        let mut info = TcpSynInfo {
+            hop_limit,
+            tos,
+            flow_label,
+            window: segment.window_len(),
+            mss: None,
+            window_scale: None,
+            sack_permitted: false,
+            timestamps: None,
+            options: segment.options().to_vec(),
+        };
+        let mut options = segment.options();
+        while !options.is_empty() {
+            let Ok((rest, option)) = TcpOption::parse(options) else {
+                break;
+            };
+            match option {
+                TcpOption::EndOfList => break,
+                TcpOption::MaxSegmentSize(mss) => info.mss = Some(mss),
+                TcpOption::WindowScale(shift) => info.window_scale = Some(shift),
+                TcpOption::SackPermitted => info.sack_permitted = true,
+                TcpOption::TimeStamp { tsval, tsecr } => info.timestamps = Some((tsval, tsecr)),
+                _ => {}
+            }
+            options = rest;
+        }
+        Some(info)
+    }
+}
+
+/// Statistics of a connection, gathered from the segments it exchanges since
+/// smoltcp keeps most of them private.
+pub(crate) struct TcpFlowStats {
+    created: Instant,
+    local_window_shift: Option<u8>,
+    peer_window_shift: Option<u8>,
+    /// Oldest sequence number not acknowledged by the peer.
+    snd_una: Option<u32>,
+    /// End of the highest sequence number sent.
+    snd_max: Option<u32>,
+    /// End of the highest sequence number received.
+    rcv_max: Option<u32>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    send_window: usize,
+    recv_window: usize,
+    /// End of the segment timed for the RTT estimate, and when it was sent.
+    rtt_probe: Option<(u32, Instant)>,
+    srtt: Option<Duration>,
+    retransmissions: u64,
+    fin_received: bool,
+    reset_received: bool,
+    /// MTU reported by ICMP for the path toward the TUN side host, if lower
+    /// than the interface's.
+    path_mtu: Option<usize>,
+}
+
+impl TcpFlowStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            local_window_shift: None,
+            peer_window_shift: None,
+            snd_una: None,
+            snd_max: None,
+            rcv_max: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            send_window: 0,
+            recv_window: 0,
+            rtt_probe: None,
+            srtt: None,
+            retransmissions: 0,
+            fin_received: false,
+            reset_received: false,
+            path_mtu: None,
+        }
+    }
+
+    pub(crate) fn info(&self, state: TcpState, send_queue: usize, recv_queue: usize) -> TcpInfo {
+        TcpInfo {
+            state,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            send_queue,
+            recv_queue,
+            send_window: self.send_window,
+            recv_window: self.recv_window,
+            rtt: self.srtt,
+            retransmissions: self.retransmissions,
+            age: self.created.elapsed(),
+        }
+    }
+
+    /// Whether the peer has sent a FIN.
+    pub(crate) fn fin_received(&self) -> bool {
+        self.fin_received
+    }
+
+    /// Whether the last segment of the connection is a RST from the peer.
+    ///
+    /// The socket closing right after tells that it has accepted the RST,
+    /// smoltcp ignores those outside of the window and answers the others.
+    pub(crate) fn reset_received(&self) -> bool {
+        self.reset_received
+    }
+
+    /// Lower the path MTU to `mtu`, it is never raised back. Returns whether
+    /// it has been lowered.
+    ///
+    /// `seq_number` is the sequence number quoted by the ICMP error, which is
+    /// ignored unless the segment is in flight (RFC 5927 4.1).
+    pub(crate) fn lower_path_mtu(&mut self, mtu: usize, seq_number: u32) -> bool {
+        let in_flight = match (self.snd_una, self.snd_max) {
+            (Some(snd_una), Some(snd_max)) => {
+                !seq_after(snd_una, seq_number) && seq_after(snd_max, seq_number)
+            }
+            _ => false,
+        };
+        if !in_flight || self.path_mtu.is_some_and(|path_mtu| mtu >= path_mtu) {
+            return false;
+        }
+        self.path_mtu = Some(mtu);
+        true
+    }
+
+    /// Window scaling is in effect only if both sides asked for it (RFC 7323 2.2).
+    fn window_shifts(&self) -> (u8, u8) {
+        match (self.local_window_shift, self.peer_window_shift) {
+            (Some(local), Some(peer)) => (local, peer),
+            _ => (0, 0),
+        }
+    }
+
+    fn ingress(&mut self, segment: &TcpPacket<&[u8]>) {
+        self.reset_received = segment.rst();
+        if segment.rst() {
+            return;
+        }
+        if segment.fin() {
+            self.fin_received = true;
+        }
+
+        if segment.syn() {
+            self.peer_window_shift = window_shift(segment);
+        } else {
+            self.send_window = (segment.window_len() as usize) << self.window_shifts().1;
+        }
+
+        if let (true, Some(snd_una), Some(snd_max)) = (segment.ack(), self.snd_una, self.snd_max) {
+            let ack_number = segment.ack_number().0 as u32;
+            if seq_after(ack_number, snd_una) && !seq_after(ack_number, snd_max) {
+                self.snd_una = Some(ack_number);
+            }
+        }
+
+        let seq_end = (segment.seq_number().0 as u32).wrapping_add(segment.segment_len() as u32);
+        match self.rcv_max {
+            Some(rcv_max) if seq_after(seq_end, rcv_max) => {
+                let new = seq_end.wrapping_sub(rcv_max) as usize;
+                self.bytes_received += new.min(segment.payload().len()) as u64;
+                self.rcv_max = Some(seq_end);
+            }
+            Some(_) => {}
+            None => self.rcv_max = Some(seq_end),
+        }
+
+        if let Some((probe_end, sent_at)) = self.rtt_probe {
+            let ack_number = segment.ack_number().0 as u32;
+            if segment.ack() && !seq_after(probe_end, ack_number) {
+                // RFC 6298 2.3
+                let rtt = sent_at.elapsed();
+                self.srtt = Some(match self.srtt {
+                    Some(srtt) => (srtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+                self.rtt_probe = None;
+            }
+        }
+    }
+
+    fn egress(&mut self, segment: &TcpPacket<&[u8]>) {
+        self.reset_received = false;
+        if segment.syn() {
+            self.local_window_shift = window_shift(segment);
+        } else {
+            self.recv_window = (segment.window_len() as usize) << self.window_shifts().0;
+        }
+
+        let seq_len = segment.segment_len() as u32;
+        let seq_end = (segment.seq_number().0 as u32).wrapping_add(seq_len);
+        match self.snd_max {
+            Some(snd_max) if seq_after(seq_end, snd_max) => {
+                let new = seq_end.wrapping_sub(snd_max) as usize;
+                self.bytes_sent += new.min(segment.payload().len()) as u64;
+                self.snd_max = Some(seq_end);
+                if self.rtt_probe.is_none() {
+                    self.rtt_probe = Some((seq_end, Instant::now()));
+                }
+            }
+            // smoltcp's keep-alive resends the last byte sent, it isn't a loss.
+            Some(snd_max) if seq_len == 1 && seq_end == snd_max && !segment.fin() => {}
+            Some(_) if seq_len > 0 => {
+                self.retransmissions += 1;
+                // Karn's algorithm, retransmitted segments aren't timed.
+                self.rtt_probe = None;
+            }
+            Some(_) => {}
+            None => {
+                self.snd_una = Some(segment.seq_number().0 as u32);
+                self.snd_max = Some(seq_end);
+            }
+        }
+    }
+}
+
+/// Whether `a` comes after `b` in sequence space.
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+fn window_shift(segment: &TcpPacket<&[u8]>) -> Option<u8> {
+    let mut options = segment.options();
+    while !options.is_empty() {
+        let (rest, option) = TcpOption::parse(options).ok()?;
+        match option {
+            TcpOption::EndOfList => break,
+            TcpOption::WindowScale(shift) => return Some(shift.min(14)),
+            _ => options = rest,
+        }
+    }
+    None
+}
+
+type SharedFlowStats = Arc<SpinMutex<TcpFlowStats>>;
+
+/// Feeds the segments crossing the device to the statistics of their connection.
+#[derive(Default)]
+pub(crate) struct TcpFlowMonitor {
+    flows: SpinMutex<HashMap<(SocketAddr, SocketAddr), SharedFlowStats>>,
+    /// Connections whose path MTU has been lowered.
+    lowered: AtomicUsize,
+}
+
+impl TcpFlowMonitor {
+    /// Start monitoring the connection keyed by (src, dst) of packets sent by the TUN side.
+    pub(crate) fn register(&self, tuple: (SocketAddr, SocketAddr), stats: SharedFlowStats) {
+        let old = self.flows.lock().insert(tuple, stats);
+        if let Some(old) = old {
+            self.forget(&old);
+        }
+    }
+
+    pub(crate) fn remove(&self, tuple: &(SocketAddr, SocketAddr)) {
+        let old = self.flows.lock().remove(tuple);
+        if let Some(old) = old {
+            self.forget(&old);
+        }
+    }
+
+    /// Lower the path MTU of a connection, see [`TcpFlowStats::lower_path_mtu`].
+    pub(crate) fn lower_path_mtu(
+        &self,
+        tuple: &(SocketAddr, SocketAddr),
+        mtu: usize,
+        seq_number: u32,
+    ) -> bool {
+        let flows = self.flows.lock();
+        let Some(stats) = flows.get(tuple) else {
+            return false;
+        };
+        let mut stats = stats.lock();
+        let lowered = stats.path_mtu.is_some();
+        if !stats.lower_path_mtu(mtu, seq_number) {
+            return false;
+        }
+        if !lowered {
+            self.lowered.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Whether a connection has its path MTU lowered, i.e. its segments may be split.
+    pub(crate) fn path_mtu_lowered(&self) -> bool {
+        self.lowered.load(Ordering::Relaxed) > 0
+    }
+
+    pub(crate) fn ingress(&self, frame: &[u8]) {
+        self.with_segment(frame, false, TcpFlowStats::ingress);
+    }
+
+    /// Returns the path MTU of the segment's connection, if lowered.
+    pub(crate) fn egress(&self, frame: &[u8]) -> Option<usize> {
+        self.with_segment(frame, true, |stats, segment| {
+            stats.egress(segment);
+            stats.path_mtu
+        })
+        .flatten()
+    }
+
+    fn with_segment<R>(
+        &self,
+        frame: &[u8],
+        egress: bool,
+        f: impl FnOnce(&mut TcpFlowStats, &TcpPacket<&[u8]>) -> R,
+    ) -> Option<R> {
+        let packet = IpPacket::new_checked(frame).ok()?;
+        if packet.protocol() != IpProtocol::Tcp {
+            return None;
+        }
+        let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+
+        let src_addr = SocketAddr::new(packet.src_addr(), segment.src_port());
+        let dst_addr = SocketAddr::new(packet.dst_addr(), segment.dst_port());
+        let tuple = if egress {
+            (dst_addr, src_addr)
+        } else {
+            (src_addr, dst_addr)
+        };
+
+        let flows = self.flows.lock();
+        let stats = flows.get(&tuple)?;
+        let result = f(&mut stats.lock(), &segment);
+        Some(result)
+    }
+
+    fn forget(&self, stats: &SharedFlowStats) {
+        if stats.lock().path_mtu.is_some() {
+            self.lowered.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -1,4 +1,10 @@
+mod backlog;
+mod bind;
+mod buffer;
+mod cookie;
 mod device;
+mod memory;
+mod pmtu;
 
 mod runner;
 pub use runner::Runner;
@@ -9,11 +15,22 @@ pub use packet::AnyIpPktFrame;
 mod filter;
 pub use filter::{IpFilter, IpFilters};
 
+mod limit;
+pub use limit::{TcpLimitStats, TcpLimits};
+
+mod info;
+pub use info::{TcpInfo, TcpSynInfo};
+
 pub mod udp;
 pub use udp::UdpSocket;
 
 pub mod tcp;
-pub use tcp::{TcpListener, TcpStream};
+/// The smoltcp types of the TCP API.
+pub use smoltcp::socket::tcp::{CongestionControl, State as TcpState};
+pub use tcp::{
+    OwnedReadHalf, OwnedWriteHalf, PendingTcpStream, ReuniteError, TcpCloseReason, TcpConfig,
+    TcpConnector, TcpListener, TcpListenerHandle, TcpReject, TcpStream,
+};
 
 pub mod stack;
 pub use stack::{Stack, StackBuilder};
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use spin::Mutex as SpinMutex;
+
+use crate::{memory::TcpMemory, tcp::TcpReject};
+
+/// Limits protecting the stack from SYN floods and memory exhaustion.
+///
+/// Every limit is disabled by default.
+#[derive(Debug, Clone)]
+pub struct TcpLimits {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_syn_rate: Option<u32>,
+    max_memory: Option<usize>,
+    backlog: Option<usize>,
+    accept_timeout: Option<Duration>,
+    reject: TcpReject,
+}
+
+impl Default for TcpLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_syn_rate: None,
+            max_memory: None,
+            backlog: None,
+            accept_timeout: None,
+            reject: TcpReject::Reset,
+        }
+    }
+}
+
+impl TcpLimits {
+    /// Maximum number of concurrent TCP connections, pending ones included.
+    pub fn max_connections(mut self, max: Option<usize>) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Maximum number of concurrent TCP connections from a single source IP.
+    pub fn max_connections_per_ip(mut self, max: Option<usize>) -> Self {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    /// Maximum number of new connections (SYNs) admitted per second.
+    pub fn max_syn_rate(mut self, max: Option<u32>) -> Self {
+        self.max_syn_rate = max;
+        self
+    }
+
+    /// Maximum memory taken by the buffers of all TCP connections.
+    ///
+    /// Buffers never go below [`TcpConfig::min_buffer_size`], the budget
+    /// limits how large they grow.
+    ///
+    /// [`TcpConfig::min_buffer_size`]: crate::TcpConfig::min_buffer_size
+    pub fn max_memory(mut self, max: Option<usize>) -> Self {
+        self.max_memory = max;
+        self
+    }
+
+    /// Maximum number of connections waiting for each listener to take them.
+    pub fn backlog(mut self, max: Option<usize>) -> Self {
+        self.backlog = max;
+        self
+    }
+
+    /// Reset the connections a listener hasn't accepted within this time.
+    pub fn accept_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.accept_timeout = timeout;
+        self
+    }
+
+    /// How SYNs exceeding a limit are answered, [`TcpReject::Reset`] by default.
+    pub fn reject(mut self, reject: TcpReject) -> Self {
+        self.reject = reject;
+        self
+    }
+}
+
+/// Counters of SYNs refused by [`TcpLimits`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TcpLimitStats {
+    /// Refused because of [`TcpLimits::max_connections`].
+    pub rejected_by_connections: u64,
+    /// Refused because of [`TcpLimits::max_connections_per_ip`].
+    pub rejected_by_connections_per_ip: u64,
+    /// Refused because of [`TcpLimits::max_syn_rate`].
+    pub rejected_by_syn_rate: u64,
+    /// Refused because of [`TcpLimits::backlog`].
+    pub rejected_by_backlog: u64,
+    /// Reset because of [`TcpLimits::accept_timeout`].
+    pub accept_timeouts: u64,
+    /// Bytes currently taken by TCP buffers, see [`TcpLimits::max_memory`].
+    pub memory_used: usize,
+}
+
+/// Tracks admitted connections against [`TcpLimits`].
+pub(crate) struct TcpLimiter {
+    limits: TcpLimits,
+    connections: AtomicUsize,
+    connections_per_ip: SpinMutex<HashMap<IpAddr, usize>>,
+    syn_window: SpinMutex<(Instant, u32)>,
+    rejected_by_connections: AtomicU64,
+    rejected_by_connections_per_ip: AtomicU64,
+    rejected_by_syn_rate: AtomicU64,
+    rejected_by_backlog: AtomicU64,
+    accept_timeouts: AtomicU64,
+    memory: Arc<TcpMemory>,
+}
+
+impl TcpLimiter {
+    pub(crate) fn new(limits: TcpLimits) -> Self {
+        Self {
+            memory: Arc::new(TcpMemory::new(limits.max_memory)),
+            limits,
+            connections: AtomicUsize::new(0),
+            connections_per_ip: SpinMutex::new(HashMap::new()),
+            syn_window: SpinMutex::new((Instant::now(), 0)),
+            rejected_by_connections: AtomicU64::new(0),
+            rejected_by_connections_per_ip: AtomicU64::new(0),
+            rejected_by_syn_rate: AtomicU64::new(0),
+            rejected_by_backlog: AtomicU64::new(0),
+            accept_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Admit a new connection from `src_ip`, it must be [released](Self::release) later.
+    ///
+    /// Returns how the SYN should be refused if a limit is exceeded.
+    pub(crate) fn admit(&self, src_ip: IpAddr) -> Result<(), TcpReject> {
+        if let Some(max_syn_rate) = self.limits.max_syn_rate {
+            let mut syn_window = self.syn_window.lock();
+            let now = Instant::now();
+            if now.duration_since(syn_window.0) >= Duration::from_secs(1) {
+                *syn_window = (now, 0);
+            }
+            if syn_window.1 >= max_syn_rate {
+                self.rejected_by_syn_rate.fetch_add(1, Ordering::Relaxed);
+                return Err(self.limits.reject);
+            }
+            syn_window.1 += 1;
+        }
+
+        let max_connections = self.limits.max_connections.unwrap_or(usize::MAX);
+        let admitted = self
+            .connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max_connections).then_some(n + 1)
+            });
+        if admitted.is_err() {
+            self.rejected_by_connections.fetch_add(1, Ordering::Relaxed);
+            return Err(self.limits.reject);
+        }
+
+        let mut connections_per_ip = self.connections_per_ip.lock();
+        let n = connections_per_ip.entry(src_ip).or_default();
+        if *n >= self.limits.max_connections_per_ip.unwrap_or(usize::MAX) {
+            drop(connections_per_ip);
+            self.connections.fetch_sub(1, Ordering::AcqRel);
+            self.rejected_by_connections_per_ip
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(self.limits.reject);
+        }
+        *n += 1;
+
+        Ok(())
+    }
+
+    /// Release a connection admitted by [`Self::admit`].
+    pub(crate) fn release(&self, src_ip: IpAddr) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+
+        let mut connections_per_ip = self.connections_per_ip.lock();
+        if let Some(n) = connections_per_ip.get_mut(&src_ip) {
+            *n -= 1;
+            if *n == 0 {
+                connections_per_ip.remove(&src_ip);
+            }
+        }
+    }
+
+    /// Admit a connection to a listener `queued` connections are waiting for.
+    pub(crate) fn admit_backlog(&self, queued: usize) -> Result<(), TcpReject> {
+        if queued >= self.limits.backlog.unwrap_or(usize::MAX) {
+            self.rejected_by_backlog.fetch_add(1, Ordering::Relaxed);
+            return Err(self.limits.reject);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn accept_timeout(&self) -> Option<Duration> {
+        self.limits.accept_timeout
+    }
+
+    /// Count a connection reset by the accept timeout.
+    pub(crate) fn accept_timed_out(&self) {
+        self.accept_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn memory(&self) -> &Arc<TcpMemory> {
+        &self.memory
+    }
+
+    pub(crate) fn stats(&self) -> TcpLimitStats {
+        TcpLimitStats {
+            rejected_by_connections: self.rejected_by_connections.load(Ordering::Relaxed),
+            rejected_by_connections_per_ip: self
+                .rejected_by_connections_per_ip
+                .load(Ordering::Relaxed),
+            rejected_by_syn_rate: self.rejected_by_syn_rate.load(Ordering::Relaxed),
+            rejected_by_backlog: self.rejected_by_backlog.load(Ordering::Relaxed),
+            accept_timeouts: self.accept_timeouts.load(Ordering::Relaxed),
+            memory_used: self.memory.used(),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory budget shared by the buffers of all TCP connections.
+pub(crate) struct TcpMemory {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl TcpMemory {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: limit.unwrap_or(usize::MAX),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve up to `max` bytes, returns the reserved size.
+    ///
+    /// At least `min` bytes are always reserved, even over the limit, so that
+    /// every connection can make progress.
+    pub(crate) fn reserve(&self, min: usize, max: usize) -> usize {
+        let mut reserved = 0;
+        let _ = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                reserved = self.limit.saturating_sub(used).min(max).max(min);
+                Some(used.saturating_add(reserved))
+            });
+        reserved
+    }
+
+    pub(crate) fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+}
@@ -1,9 +1,26 @@
 use std::net::IpAddr;
 
-use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+use etherparse::{
+    icmpv4, icmpv6, Icmpv4Type, Icmpv6Type, PacketBuilder, PacketBuilderStep, TcpHeader,
+};
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket};
 
 pub type AnyIpPktFrame = Vec<u8>;
 
+// RFC 1812 4.3.2.3: an ICMPv4 error should not exceed 576 bytes.
+const ICMPV4_ERROR_MAX_QUOTE: usize = 576 - 20 - 8;
+// RFC 4443 2.4: an ICMPv6 error must not exceed the minimum IPv6 MTU.
+const ICMPV6_ERROR_MAX_QUOTE: usize = 1280 - 40 - 8;
+
+/// ICMP destination unreachable reasons used to refuse a flow.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(super) enum IcmpUnreachable {
+    Network,
+    Host,
+    Port,
+    Prohibited,
+}
+
 #[derive(Debug)]
 pub(super) enum IpPacket<T: AsRef<[u8]>> {
     Ipv4(Ipv4Packet<T>),
@@ -51,3 +68,162 @@ impl<'a, T: AsRef<[u8]> + ?Sized> IpPacket<&'a T> {
         }
     }
 }
+
+/// Build the TCP RST answering the TCP segment in `frame`, following RFC 793's
+/// reset generation rules.
+pub(super) fn tcp_reset_reply(frame: &[u8]) -> Option<AnyIpPktFrame> {
+    let packet = IpPacket::new_checked(frame).ok()?;
+    let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+    if segment.rst() {
+        return None;
+    }
+
+    let (seq_number, ack_number) = if segment.ack() {
+        (segment.ack_number().0 as u32, None)
+    } else {
+        let seq_len = segment.payload().len() + segment.syn() as usize + segment.fin() as usize;
+        let ack_number = (segment.seq_number() + seq_len).0 as u32;
+        (0, Some(ack_number))
+    };
+
+    let build = |builder: PacketBuilderStep<TcpHeader>| {
+        let builder = match ack_number {
+            Some(ack_number) => builder.rst().ack(ack_number),
+            None => builder.rst(),
+        };
+        let mut reply = Vec::with_capacity(builder.size(0));
+        builder.write(&mut reply, &[]).ok()?;
+        Some(reply)
+    };
+
+    let (src_port, dst_port) = (segment.dst_port(), segment.src_port());
+    match packet {
+        IpPacket::Ipv4(ref packet) => build(
+            PacketBuilder::ipv4(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                .tcp(src_port, dst_port, seq_number, 0),
+        ),
+        IpPacket::Ipv6(ref packet) => build(
+            PacketBuilder::ipv6(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                .tcp(src_port, dst_port, seq_number, 0),
+        ),
+    }
+}
+
+/// Build the ICMP destination unreachable error quoting `frame`.
+pub(super) fn icmp_unreachable_reply(
+    frame: &[u8],
+    reason: IcmpUnreachable,
+) -> Option<AnyIpPktFrame> {
+    let packet = IpPacket::new_checked(frame).ok()?;
+
+    let mut reply = Vec::new();
+    match packet {
+        IpPacket::Ipv4(ref packet) => {
+            let header = match reason {
+                IcmpUnreachable::Network => icmpv4::DestUnreachableHeader::Network,
+                IcmpUnreachable::Host => icmpv4::DestUnreachableHeader::Host,
+                IcmpUnreachable::Port => icmpv4::DestUnreachableHeader::Port,
+                IcmpUnreachable::Prohibited => icmpv4::DestUnreachableHeader::FilterProhibited,
+            };
+            let quote = &frame[..frame.len().min(ICMPV4_ERROR_MAX_QUOTE)];
+            let builder =
+                PacketBuilder::ipv4(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                    .icmpv4(Icmpv4Type::DestinationUnreachable(header));
+            reply.reserve(builder.size(quote.len()));
+            builder.write(&mut reply, quote).ok()?;
+        }
+        IpPacket::Ipv6(ref packet) => {
+            let code = match reason {
+                IcmpUnreachable::Network => icmpv6::DestUnreachableCode::NoRoute,
+                IcmpUnreachable::Host => icmpv6::DestUnreachableCode::Address,
+                IcmpUnreachable::Port => icmpv6::DestUnreachableCode::Port,
+                IcmpUnreachable::Prohibited => icmpv6::DestUnreachableCode::Prohibited,
+            };
+            let quote = &frame[..frame.len().min(ICMPV6_ERROR_MAX_QUOTE)];
+            let builder =
+                PacketBuilder::ipv6(packet.dst_addr().octets(), packet.src_addr().octets(), 64)
+                    .icmpv6(Icmpv6Type::DestinationUnreachable(code));
+            reply.reserve(builder.size(quote.len()));
+            builder.write(&mut reply, quote).ok()?;
+        }
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{IpNumber, NetSlice, SlicedPacket, TransportSlice};
+
+    use super::*;
+
+    fn ipv4_segment(
+        seq_number: u32,
+        flags: impl FnOnce(PacketBuilderStep<TcpHeader>) -> PacketBuilderStep<TcpHeader>,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let builder = flags(
+            PacketBuilder::ipv4([10, 0, 0, 2], [1, 2, 3, 4], 64).tcp(40000, 80, seq_number, 1000),
+        );
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        frame
+    }
+
+    /// The TCP header of the reset in `frame`, checking it is sent back to
+    /// the origin of the segment it answers.
+    fn reset(frame: &[u8]) -> TcpHeader {
+        let packet = SlicedPacket::from_ip(frame).unwrap();
+        let Some(NetSlice::Ipv4(ip)) = packet.net else {
+            panic!("not an IPv4 packet");
+        };
+        assert_eq!(ip.header().source(), [1, 2, 3, 4]);
+        assert_eq!(ip.header().destination(), [10, 0, 0, 2]);
+        assert_eq!(ip.header().protocol(), IpNumber::TCP);
+        let Some(TransportSlice::Tcp(tcp)) = packet.transport else {
+            panic!("not a TCP segment");
+        };
+        assert_eq!((tcp.source_port(), tcp.destination_port()), (80, 40000));
+        assert!(tcp.rst());
+        assert!(tcp.payload().is_empty());
+        let header = tcp.to_header();
+        let checksum = header
+            .calc_checksum_ipv4(&ip.header().to_header(), &[])
+            .unwrap();
+        assert_eq!(header.checksum, checksum);
+        header
+    }
+
+    #[test]
+    fn reset_acknowledging_syn() {
+        let syn = ipv4_segment(1000, |builder| builder.syn(), &[]);
+        let header = reset(&tcp_reset_reply(&syn).unwrap());
+        assert_eq!(header.sequence_number, 0);
+        assert!(header.ack);
+        assert_eq!(header.acknowledgment_number, 1001);
+    }
+
+    #[test]
+    fn reset_acknowledging_segment() {
+        let segment = ipv4_segment(u32::MAX - 1, |builder| builder.fin(), &[0; 10]);
+        let header = reset(&tcp_reset_reply(&segment).unwrap());
+        assert_eq!(header.sequence_number, 0);
+        assert!(header.ack);
+        assert_eq!(header.acknowledgment_number, 9);
+    }
+
+    #[test]
+    fn reset_taking_ack_number() {
+        let segment = ipv4_segment(1000, |builder| builder.ack(5000).psh(), &[0; 10]);
+        let header = reset(&tcp_reset_reply(&segment).unwrap());
+        assert_eq!(header.sequence_number, 5000);
+        assert!(!header.ack);
+        assert_eq!(header.acknowledgment_number, 0);
+    }
+
+    #[test]
+    fn no_reset_for_reset() {
+        let segment = ipv4_segment(1000, |builder| builder.rst(), &[]);
+        assert_eq!(tcp_reset_reply(&segment), None);
+        assert_eq!(tcp_reset_reply(&segment[..30]), None);
+    }
+}
@@ -0,0 +1,396 @@
+use std::net::{IpAddr, SocketAddr};
+
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket};
+
+use crate::packet::{AnyIpPktFrame, IpPacket};
+
+/// Smallest MTU an IPv4 path may be lowered to, as hosts must reassemble
+/// datagrams of this size (RFC 1122 3.3.2).
+const MIN_IPV4_MTU: usize = 576;
+/// Minimum IPv6 link MTU (RFC 8200 5).
+const MIN_IPV6_MTU: usize = 1280;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+/// Largest IPv4 or TCP header, options included.
+const MAX_HEADER_LEN: usize = 60;
+
+/// Smallest MTU accepted for the TUN interface.
+pub(crate) const MIN_MTU: usize = MIN_IPV4_MTU;
+
+/// The path MTU reported by an ICMP "fragmentation needed" or ICMPv6 "packet
+/// too big" quoting a TCP segment sent by the stack, with the tuple of the
+/// connection as sent by the TUN side and the quoted sequence number.
+pub(crate) fn too_big(frame: &[u8]) -> Option<((SocketAddr, SocketAddr), u32, usize)> {
+    let packet = IpPacket::new_checked(frame).ok()?;
+    let icmp = packet.payload();
+    if icmp.len() < 8 {
+        return None;
+    }
+
+    let (mtu, inner, min_mtu) = match packet {
+        // Destination unreachable, fragmentation needed (RFC 1191 4).
+        IpPacket::Ipv4(_) if packet.protocol() == IpProtocol::Icmp => {
+            if icmp[0] != 3 || icmp[1] != 4 {
+                return None;
+            }
+            let mtu = u16::from_be_bytes([icmp[6], icmp[7]]) as usize;
+            (mtu, &icmp[8..], MIN_IPV4_MTU)
+        }
+        // Packet too big (RFC 4443 3.2).
+        IpPacket::Ipv6(_) if packet.protocol() == IpProtocol::Icmpv6 => {
+            if icmp[0] != 2 {
+                return None;
+            }
+            let mtu = u32::from_be_bytes([icmp[4], icmp[5], icmp[6], icmp[7]]) as usize;
+            (mtu, &icmp[8..], MIN_IPV6_MTU)
+        }
+        _ => return None,
+    };
+    // Routers predating RFC 1191 report no MTU.
+    if mtu == 0 {
+        return None;
+    }
+
+    // The quoted packet is truncated, only its headers and the first 8 bytes
+    // of the segment, up to the sequence number, are read.
+    let (src_ip, dst_ip, quoted): (IpAddr, IpAddr, &[u8]) = match inner.first()? >> 4 {
+        4 => {
+            let header_len = ((inner[0] & 0x0f) as usize) * 4;
+            if header_len < IPV4_HEADER_LEN || inner.len() < header_len + 8 {
+                return None;
+            }
+            let packet = Ipv4Packet::new_unchecked(inner);
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                &inner[header_len..],
+            )
+        }
+        6 => {
+            if inner.len() < IPV6_HEADER_LEN + 8 {
+                return None;
+            }
+            let packet = Ipv6Packet::new_unchecked(inner);
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                &inner[IPV6_HEADER_LEN..],
+            )
+        }
+        _ => return None,
+    };
+    let src_port = u16::from_be_bytes([quoted[0], quoted[1]]);
+    let dst_port = u16::from_be_bytes([quoted[2], quoted[3]]);
+    let seq_number = u32::from_be_bytes([quoted[4], quoted[5], quoted[6], quoted[7]]);
+
+    // The segment went from the stack to the TUN side.
+    let tuple = (
+        SocketAddr::new(dst_ip, dst_port),
+        SocketAddr::new(src_ip, src_port),
+    );
+    Some((tuple, seq_number, mtu.max(min_mtu)))
+}
+
+/// Lower the MSS option of a SYN to what fits in `mtu`.
+pub(crate) fn clamp_mss(frame: &mut [u8], mtu: usize) {
+    let Ok(packet) = IpPacket::new_checked(&frame[..]) else {
+        return;
+    };
+    if packet.protocol() != IpProtocol::Tcp {
+        return;
+    }
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    let (ip_header_len, min_header_len) = match packet {
+        IpPacket::Ipv4(ref packet) => (packet.header_len() as usize, IPV4_HEADER_LEN),
+        IpPacket::Ipv6(_) => (IPV6_HEADER_LEN, IPV6_HEADER_LEN),
+    };
+    let max_mss = mtu
+        .saturating_sub(min_header_len + TCP_HEADER_LEN)
+        .min(u16::MAX as usize) as u16;
+
+    let Ok(mut segment) = TcpPacket::new_checked(&mut frame[ip_header_len..]) else {
+        return;
+    };
+    if !segment.syn() {
+        return;
+    }
+
+    let options = segment.options_mut();
+    let mut clamped = false;
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // End of option list.
+            0 => break,
+            // No-operation.
+            1 => i += 1,
+            kind => {
+                let Some(&len) = options.get(i + 1) else {
+                    break;
+                };
+                let len = len as usize;
+                if len < 2 || i + len > options.len() {
+                    break;
+                }
+                if kind == 2 && len == 4 {
+                    let mss = u16::from_be_bytes([options[i + 2], options[i + 3]]);
+                    if mss > max_mss {
+                        options[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                        clamped = true;
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+
+    if clamped {
+        segment.fill_checksum(&src_addr.into(), &dst_addr.into());
+    }
+}
+
+/// How many segments [`segment`] may split a frame of up to `mtu` bytes into,
+/// with the lowest path MTU [`too_big`] reports.
+pub(crate) fn max_segments(mtu: usize) -> usize {
+    let payload = mtu.saturating_sub(IPV4_HEADER_LEN + TCP_HEADER_LEN);
+    payload.div_ceil(MIN_IPV4_MTU - 2 * MAX_HEADER_LEN).max(1)
+}
+
+/// Split a TCP segment larger than `mtu` into segments that fit, as the
+/// stack's interface only knows the MTU of the TUN interface.
+pub(crate) fn segment(frame: AnyIpPktFrame, mtu: usize) -> Vec<AnyIpPktFrame> {
+    let Ok(packet) = IpPacket::new_checked(&frame[..]) else {
+        return vec![frame];
+    };
+    if packet.protocol() != IpProtocol::Tcp {
+        return vec![frame];
+    }
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    let (ipv4, ip_header_len) = match packet {
+        IpPacket::Ipv4(ref packet) => (true, packet.header_len() as usize),
+        IpPacket::Ipv6(_) => (false, IPV6_HEADER_LEN),
+    };
+    let Ok(segment) = TcpPacket::new_checked(packet.payload()) else {
+        return vec![frame];
+    };
+    let header_len = ip_header_len + segment.header_len() as usize;
+    let max_payload = mtu.saturating_sub(header_len);
+    if segment.syn() || max_payload == 0 || segment.payload().len() <= max_payload {
+        return vec![frame];
+    }
+    let (seq_number, fin, psh) = (segment.seq_number(), segment.fin(), segment.psh());
+
+    let (headers, payload) = frame.split_at(header_len);
+    let chunks = payload.chunks(max_payload);
+    let last = chunks.len() - 1;
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut buffer = Vec::with_capacity(header_len + chunk.len());
+            buffer.extend_from_slice(headers);
+            buffer.extend_from_slice(chunk);
+
+            if ipv4 {
+                let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+                packet.set_total_len((header_len + chunk.len()) as u16);
+                packet.fill_checksum();
+            } else {
+                let mut packet = Ipv6Packet::new_unchecked(&mut buffer[..]);
+                packet.set_payload_len((header_len - IPV6_HEADER_LEN + chunk.len()) as u16);
+            }
+
+            let mut segment = TcpPacket::new_unchecked(&mut buffer[ip_header_len..]);
+            segment.set_seq_number(seq_number + index * max_payload);
+            // Only the last segment carries the FIN and PSH.
+            segment.set_fin(fin && index == last);
+            segment.set_psh(psh && index == last);
+            segment.fill_checksum(&src_addr.into(), &dst_addr.into());
+            buffer
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{
+        icmpv4::DestUnreachableHeader, Icmpv4Type, Icmpv6Type, PacketBuilder, TcpOptionElement,
+    };
+    use smoltcp::wire::{IpAddress, TcpOption};
+
+    use super::*;
+
+    fn ipv4_segment(seq_number: u32, payload: &[u8], options: &[TcpOptionElement]) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4([1, 2, 3, 4], [10, 0, 0, 2], 64)
+            .tcp(80, 40000, seq_number, 1000)
+            .ack(7)
+            .psh()
+            .fin()
+            .options(options)
+            .unwrap();
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        frame
+    }
+
+    fn ipv4_syn(mss: u16) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4([10, 0, 0, 2], [1, 2, 3, 4], 64)
+            .tcp(40000, 80, 1000, 1000)
+            .syn()
+            .options(&[
+                TcpOptionElement::Noop,
+                TcpOptionElement::WindowScale(7),
+                TcpOptionElement::MaximumSegmentSize(mss),
+            ])
+            .unwrap();
+        let mut frame = Vec::with_capacity(builder.size(0));
+        builder.write(&mut frame, &[]).unwrap();
+        frame
+    }
+
+    /// The MSS option of the segment in `frame`, checking its checksum.
+    fn mss(frame: &[u8]) -> Option<u16> {
+        let packet = Ipv4Packet::new_checked(frame).unwrap();
+        let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+        assert!(segment.verify_checksum(
+            &IpAddress::Ipv4(packet.src_addr()),
+            &IpAddress::Ipv4(packet.dst_addr())
+        ));
+        let mut options = segment.options();
+        while !options.is_empty() {
+            let (rest, option) = TcpOption::parse(options).unwrap();
+            if let TcpOption::MaxSegmentSize(mss) = option {
+                return Some(mss);
+            }
+            options = rest;
+        }
+        None
+    }
+
+    #[test]
+    fn clamp_mss_to_mtu() {
+        let mut syn = ipv4_syn(1460);
+        clamp_mss(&mut syn, 1280);
+        assert_eq!(mss(&syn), Some(1240));
+
+        // Already small enough.
+        let mut syn = ipv4_syn(536);
+        clamp_mss(&mut syn, 1280);
+        assert_eq!(mss(&syn), Some(536));
+
+        // Only SYNs are touched.
+        let mut segment = ipv4_segment(1, &[], &[TcpOptionElement::MaximumSegmentSize(1460)]);
+        clamp_mss(&mut segment, 1280);
+        assert_eq!(mss(&segment), Some(1460));
+    }
+
+    #[test]
+    fn segment_to_path_mtu() {
+        let payload: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let frame = ipv4_segment(u32::MAX - 100, &payload, &[]);
+        let segments = segment(frame, 1000);
+        assert_eq!(segments.len(), 3);
+
+        let mut received = Vec::new();
+        for (index, frame) in segments.iter().enumerate() {
+            assert!(frame.len() <= 1000);
+            let packet = Ipv4Packet::new_checked(&frame[..]).unwrap();
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.total_len() as usize, frame.len());
+            let tcp = TcpPacket::new_checked(packet.payload()).unwrap();
+            assert!(tcp.verify_checksum(
+                &IpAddress::Ipv4(packet.src_addr()),
+                &IpAddress::Ipv4(packet.dst_addr())
+            ));
+            // Sequence numbers follow the payload, wrapping around.
+            let seq_number = (u32::MAX - 100).wrapping_add(received.len() as u32);
+            assert_eq!(tcp.seq_number().0 as u32, seq_number);
+            assert_eq!(tcp.ack_number().0, 7);
+            let last = index == segments.len() - 1;
+            assert_eq!(tcp.fin(), last);
+            assert_eq!(tcp.psh(), last);
+            received.extend_from_slice(tcp.payload());
+        }
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn segment_keeps_what_fits() {
+        let frame = ipv4_segment(1, &[0; 500], &[]);
+        assert_eq!(segment(frame.clone(), 1000), vec![frame]);
+        let syn = ipv4_syn(1460);
+        assert_eq!(segment(syn.clone(), 40), vec![syn]);
+    }
+
+    #[test]
+    fn max_segments_at_lowest_path_mtu() {
+        assert_eq!(max_segments(MIN_IPV4_MTU), 2);
+        assert_eq!(max_segments(1500), 4);
+
+        let frame = ipv4_segment(1, &vec![0; 1500 - 40], &[]);
+        assert!(segment(frame, MIN_IPV4_MTU).len() <= max_segments(1500));
+        let options = vec![TcpOptionElement::Noop; 40];
+        let frame = ipv4_segment(1, &vec![0; 1500 - 80], &options);
+        assert!(segment(frame, MIN_IPV4_MTU).len() <= max_segments(1500));
+    }
+
+    #[test]
+    fn too_big_quoting_segment() {
+        let quoted = ipv4_segment(0x1234_5678, &[0; 1200], &[]);
+        let icmp = |next_hop_mtu| {
+            let builder = PacketBuilder::ipv4([10, 0, 0, 9], [1, 2, 3, 4], 64).icmpv4(
+                Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded {
+                    next_hop_mtu,
+                }),
+            );
+            let mut frame = Vec::new();
+            builder.write(&mut frame, &quoted[..28]).unwrap();
+            frame
+        };
+
+        let tuple = (
+            "10.0.0.2:40000".parse().unwrap(),
+            "1.2.3.4:80".parse().unwrap(),
+        );
+        assert_eq!(too_big(&icmp(1000)), Some((tuple, 0x1234_5678, 1000)));
+        // Never below the minimum, and ignored without an MTU.
+        assert_eq!(too_big(&icmp(68)), Some((tuple, 0x1234_5678, MIN_IPV4_MTU)));
+        assert_eq!(too_big(&icmp(0)), None);
+
+        // Too short to quote the sequence number.
+        let builder = PacketBuilder::ipv4([10, 0, 0, 9], [1, 2, 3, 4], 64).icmpv4(
+            Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded {
+                next_hop_mtu: 1000,
+            }),
+        );
+        let mut frame = Vec::new();
+        builder.write(&mut frame, &quoted[..24]).unwrap();
+        assert_eq!(too_big(&frame), None);
+    }
+
+    #[test]
+    fn too_big_ipv6() {
+        let builder = PacketBuilder::ipv6([1; 16], [2; 16], 64)
+            .tcp(80, 40000, 42, 1000)
+            .ack(1);
+        let mut quoted = Vec::new();
+        builder.write(&mut quoted, &[0; 1400]).unwrap();
+
+        let builder = PacketBuilder::ipv6([3; 16], [1; 16], 64)
+            .icmpv6(Icmpv6Type::PacketTooBig { mtu: 1200 });
+        let mut frame = Vec::new();
+        builder.write(&mut frame, &quoted[..48]).unwrap();
+
+        let ((src_addr, dst_addr), seq_number, mtu) = too_big(&frame).unwrap();
+        assert_eq!(src_addr, SocketAddr::new(IpAddr::from([2; 16]), 40000));
+        assert_eq!(dst_addr, SocketAddr::new(IpAddr::from([1; 16]), 80));
+        assert_eq!((seq_number, mtu), (42, MIN_IPV6_MTU));
+    }
+}
@@ -1,19 +1,24 @@
 use std::{
-    net::IpAddr,
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
     task::{Context, Poll},
 };
 
 use futures::{Sink, Stream};
-use smoltcp::wire::IpProtocol;
+use smoltcp::wire::{IpProtocol, TcpPacket};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tracing::{debug, trace};
 
 use crate::{
+    device::DEFAULT_MTU,
     filter::{IpFilter, IpFilters},
+    limit::TcpLimits,
     packet::{AnyIpPktFrame, IpPacket},
+    pmtu,
     runner::Runner,
-    tcp::TcpListener,
+    tcp::{TcpConfig, TcpListener, TcpReject, TcpSettings},
     udp::UdpSocket,
 };
 
@@ -24,6 +29,12 @@ pub struct StackBuilder {
     stack_buffer_size: usize,
     udp_buffer_size: usize,
     tcp_buffer_size: usize,
+    tcp_shards: usize,
+    tcp_config: TcpConfig,
+    tcp_limits: TcpLimits,
+    tcp_syn_cookies: bool,
+    tcp_unmatched: TcpReject,
+    mtu: usize,
     ip_filters: IpFilters<'static>,
 }
 
@@ -36,6 +47,12 @@ impl Default for StackBuilder {
             stack_buffer_size: 1024,
             udp_buffer_size: 512,
             tcp_buffer_size: 512,
+            tcp_shards: 1,
+            tcp_config: TcpConfig::default(),
+            tcp_limits: TcpLimits::default(),
+            tcp_syn_cookies: false,
+            tcp_unmatched: TcpReject::Reset,
+            mtu: DEFAULT_MTU,
             ip_filters: IpFilters::with_non_broadcast(),
         }
     }
@@ -73,6 +90,51 @@ impl StackBuilder {
         self
     }
 
+    /// Number of TCP runners, each with its own smoltcp interface, 1 by default.
+    ///
+    /// Connections are spread over the runners by their 4-tuple. Spawn each
+    /// runner returned by [`Self::build_sharded`] on its own task to make use
+    /// of multiple cores.
+    pub fn tcp_shards(mut self, shards: usize) -> Self {
+        self.tcp_shards = shards.max(1);
+        self
+    }
+
+    pub fn tcp_config(mut self, config: TcpConfig) -> Self {
+        self.tcp_config = config;
+        self
+    }
+
+    pub fn tcp_limits(mut self, limits: TcpLimits) -> Self {
+        self.tcp_limits = limits;
+        self
+    }
+
+    /// Answer SYNs statelessly with SYN cookies, no state is kept for a
+    /// connection until the client completes the handshake.
+    pub fn tcp_syn_cookies(mut self, enable: bool) -> Self {
+        self.tcp_syn_cookies = enable;
+        self
+    }
+
+    /// How SYNs matching no [listener](TcpListener::bind) are answered,
+    /// [`TcpReject::Reset`] by default.
+    pub fn tcp_unmatched(mut self, reject: TcpReject) -> Self {
+        self.tcp_unmatched = reject;
+        self
+    }
+
+    /// MTU of the TUN interface, bounding the TCP segments sent and the MSS
+    /// advertised to and accepted from the TUN side. Lower values are raised
+    /// to 576.
+    ///
+    /// ICMP "fragmentation needed" and ICMPv6 "packet too big" messages lower
+    /// it further for the connection they are about.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu.max(pmtu::MIN_MTU);
+        self
+    }
+
     pub fn set_ip_filters(mut self, filters: IpFilters<'static>) -> Self {
         self.ip_filters = filters;
         self
@@ -91,6 +153,7 @@ impl StackBuilder {
         self
     }
 
+    /// Build the stack, all the TCP runners are driven by the returned one.
     #[allow(clippy::type_complexity)]
     pub fn build(
         self,
@@ -100,6 +163,23 @@ impl StackBuilder {
         Option<UdpSocket>,
         Option<TcpListener>,
     )> {
+        let (stack, mut tcp_runners, udp_socket, tcp_listener) = self.build_sharded()?;
+        let tcp_runner = match tcp_runners.len() {
+            0 => None,
+            1 => tcp_runners.pop(),
+            _ => Some(Runner::new(async move {
+                futures::future::try_join_all(tcp_runners).await?;
+                Ok(())
+            })),
+        };
+        Ok((stack, tcp_runner, udp_socket, tcp_listener))
+    }
+
+    /// Build the stack, returning one runner per [TCP shard](Self::tcp_shards).
+    #[allow(clippy::type_complexity)]
+    pub fn build_sharded(
+        self,
+    ) -> std::io::Result<(Stack, Vec<Runner>, Option<UdpSocket>, Option<TcpListener>)> {
         let (stack_tx, stack_rx) = channel(self.stack_buffer_size);
 
         let (udp_tx, udp_rx) = if self.enable_udp {
@@ -109,11 +189,12 @@ impl StackBuilder {
             (None, None)
         };
 
-        let (tcp_tx, tcp_rx) = if self.enable_tcp {
-            let (tcp_tx, tcp_rx) = channel(self.tcp_buffer_size);
-            (Some(tcp_tx), Some(tcp_rx))
+        let (tcp_tx, tcp_rx): (Vec<_>, Vec<_>) = if self.enable_tcp {
+            (0..self.tcp_shards)
+                .map(|_| channel(self.tcp_buffer_size))
+                .unzip()
         } else {
-            (None, None)
+            (Vec::new(), Vec::new())
         };
 
         // ICMP is handled by TCP's Interface.
@@ -123,18 +204,27 @@ impl StackBuilder {
             return Err(Error::new(InvalidInput, "ICMP requires TCP"));
         }
         let icmp_tx = if self.enable_icmp {
-            tcp_tx.clone()
+            tcp_tx.first().cloned()
         } else {
             None
         };
 
         let udp_socket = udp_rx.map(|udp_rx| UdpSocket::new(udp_rx, stack_tx.clone()));
 
-        let (tcp_runner, tcp_listener) = if let Some(tcp_rx) = tcp_rx {
-            let (tcp_runner, tcp_listener) = TcpListener::new(tcp_rx, stack_tx)?;
-            (Some(tcp_runner), Some(tcp_listener))
+        let tcp_shard_hasher = RandomState::new();
+        let (tcp_runners, tcp_listener) = if !tcp_rx.is_empty() {
+            let settings = TcpSettings {
+                config: self.tcp_config,
+                limits: self.tcp_limits,
+                syn_cookies: self.tcp_syn_cookies,
+                mtu: self.mtu,
+                unmatched: self.tcp_unmatched,
+            };
+            let (tcp_runners, tcp_listener) =
+                TcpListener::new(settings, tcp_rx, stack_tx, tcp_shard_hasher.clone())?;
+            (tcp_runners, Some(tcp_listener))
         } else {
-            (None, None)
+            (Vec::new(), None)
         };
 
         let stack = Stack {
@@ -143,31 +233,49 @@ impl StackBuilder {
             sink_buf: None,
             udp_tx,
             tcp_tx,
+            tcp_shard_hasher,
             icmp_tx,
         };
 
-        Ok((stack, tcp_runner, udp_socket, tcp_listener))
+        Ok((stack, tcp_runners, udp_socket, tcp_listener))
     }
 }
 
 pub struct Stack {
     ip_filters: IpFilters<'static>,
-    sink_buf: Option<(AnyIpPktFrame, IpProtocol)>,
+    sink_buf: Option<(AnyIpPktFrame, IpProtocol, usize)>,
     udp_tx: Option<Sender<AnyIpPktFrame>>,
-    tcp_tx: Option<Sender<AnyIpPktFrame>>,
+    tcp_tx: Vec<Sender<AnyIpPktFrame>>,
+    tcp_shard_hasher: RandomState,
     icmp_tx: Option<Sender<AnyIpPktFrame>>,
     stack_rx: Receiver<AnyIpPktFrame>,
 }
 
 impl Stack {
+    /// The TCP shard handling the connection of `packet`, by its 4-tuple.
+    fn tcp_shard(&self, packet: &IpPacket<&[u8]>) -> usize {
+        if self.tcp_tx.len() <= 1 {
+            return 0;
+        }
+        let ports = TcpPacket::new_checked(packet.payload())
+            .map(|segment| (segment.src_port(), segment.dst_port()))
+            .unwrap_or_default();
+        tcp_shard(
+            &self.tcp_shard_hasher,
+            SocketAddr::new(packet.src_addr(), ports.0),
+            SocketAddr::new(packet.dst_addr(), ports.1),
+            self.tcp_tx.len(),
+        )
+    }
+
     fn poll_send(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        let (item, proto) = match self.sink_buf.take() {
+        let (item, proto, shard) = match self.sink_buf.take() {
             Some(val) => val,
             None => return Poll::Ready(Ok(())),
         };
 
         let ready_res = match proto {
-            IpProtocol::Tcp => self.tcp_tx.as_mut().map(|tx| tx.try_reserve()),
+            IpProtocol::Tcp => self.tcp_tx.get(shard).map(|tx| tx.try_reserve()),
             IpProtocol::Udp => self.udp_tx.as_mut().map(|tx| tx.try_reserve()),
             IpProtocol::Icmp | IpProtocol::Icmpv6 => {
                 self.icmp_tx.as_mut().map(|tx| tx.try_reserve())
@@ -182,7 +290,7 @@ impl Stack {
         let permit = match ready_res {
             Ok(permit) => permit,
             Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
-                self.sink_buf.replace((item, proto));
+                self.sink_buf.replace((item, proto, shard));
                 return Poll::Pending;
             }
             Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
@@ -243,7 +351,19 @@ impl Sink<AnyIpPktFrame> for Stack {
             protocol,
             IpProtocol::Tcp | IpProtocol::Udp | IpProtocol::Icmp | IpProtocol::Icmpv6
         ) {
-            self.sink_buf.replace((item, protocol));
+            let (protocol, shard) = match protocol {
+                IpProtocol::Tcp => (protocol, self.tcp_shard(&packet)),
+                // Errors about a TCP segment go to the shard of its connection.
+                _ => match pmtu::too_big(&item).filter(|_| !self.tcp_tx.is_empty()) {
+                    Some(((src_addr, dst_addr), _, _)) => {
+                        let shards = self.tcp_tx.len();
+                        let shard = tcp_shard(&self.tcp_shard_hasher, src_addr, dst_addr, shards);
+                        (IpProtocol::Tcp, shard)
+                    }
+                    None => (protocol, 0),
+                },
+            };
+            self.sink_buf.replace((item, protocol, shard));
         } else {
             debug!("tun IP packet ignored (protocol: {:?})", protocol);
         }
@@ -264,6 +384,23 @@ impl Sink<AnyIpPktFrame> for Stack {
     }
 }
 
+/// The TCP shard of the connection whose packets sent by the TUN side go from
+/// `src_addr` to `dst_addr`.
+pub(crate) fn tcp_shard(
+    hasher: &RandomState,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    shards: usize,
+) -> usize {
+    let tuple = (
+        src_addr.ip(),
+        src_addr.port(),
+        dst_addr.ip(),
+        dst_addr.port(),
+    );
+    (hasher.hash_one(tuple) % shards as u64) as usize
+}
+
 fn channel_closed_err<E>(err: E) -> std::io::Error
 where
     E: Into<Box<dyn std::error::Error + Send + Sync>>,
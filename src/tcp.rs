@@ -1,42 +1,294 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    future::Future,
+    io::IoSlice,
     net::SocketAddr,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    task::{Context, Poll, Waker},
+    task::{ready, Context, Poll, Wake, Waker},
 };
 
 use futures::Stream;
 use smoltcp::{
     iface::{Config as InterfaceConfig, Interface, SocketHandle, SocketSet},
     phy::Device,
-    socket::tcp::{Socket as TcpSocket, SocketBuffer as TcpSocketBuffer, State as TcpState},
-    storage::RingBuffer,
+    socket::tcp::{
+        CongestionControl, Socket as TcpSocket, SocketBuffer as TcpSocketBuffer, State as TcpState,
+    },
     time::{Duration, Instant},
     wire::{HardwareAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv6Address, TcpPacket},
 };
-use spin::Mutex as SpinMutex;
+use spin::{Mutex as SpinMutex, MutexGuard as SpinMutexGuard};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf},
     sync::{
         mpsc::{unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
-        Notify,
+        oneshot, Notify,
     },
 };
 use tracing::{error, trace};
 
 use crate::{
+    backlog::{backlog, TcpBacklogRx, TcpBacklogSlot},
+    bind::TcpBindings,
+    buffer::{AdaptiveBuffer, BUFFER_SHRINK_IDLE},
+    cookie::{SeqTranslator, SynCookie, SynCookies},
     device::VirtualDevice,
-    packet::{AnyIpPktFrame, IpPacket},
+    info::{TcpFlowMonitor, TcpFlowStats, TcpInfo, TcpSynInfo},
+    limit::{TcpLimitStats, TcpLimiter, TcpLimits},
+    memory::TcpMemory,
+    packet::{icmp_unreachable_reply, tcp_reset_reply, AnyIpPktFrame, IcmpUnreachable, IpPacket},
+    pmtu,
+    stack::tcp_shard,
     Runner,
 };
 
 // NOTE: Default buffer could contain 20 AEAD packets
-const DEFAULT_TCP_SEND_BUFFER_SIZE: u32 = 0x3FFF * 20;
-const DEFAULT_TCP_RECV_BUFFER_SIZE: u32 = 0x3FFF * 20;
+const DEFAULT_TCP_SEND_BUFFER_SIZE: usize = 0x3FFF * 20;
+const DEFAULT_TCP_RECV_BUFFER_SIZE: usize = 0x3FFF * 20;
+const DEFAULT_TCP_MIN_BUFFER_SIZE: usize = 0x1000;
+const DEFAULT_TCP_SOCKET_BUFFER_SIZE: usize = 0x10000;
+/// smoltcp panics on larger receive buffers, its window can't scale past them.
+const MAX_TCP_SOCKET_BUFFER_SIZE: usize = 1 << 30;
+
+/// Per-connection TCP socket parameters.
+///
+/// The smoltcp socket buffers are allocated once, at the
+/// [socket buffer size](Self::socket_buffer_size). The buffers shared between
+/// the runner and the [`TcpStream`] start at the minimum size, grow up to the
+/// buffer sizes while they keep being filled up, and shrink back when idle.
+/// All of them are taken from the [memory budget](crate::TcpLimits::max_memory).
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    send_buffer_size: usize,
+    recv_buffer_size: usize,
+    min_buffer_size: usize,
+    socket_buffer_size: usize,
+    keep_alive: Option<std::time::Duration>,
+    idle_timeout: Option<std::time::Duration>,
+    ack_delay: Option<std::time::Duration>,
+    nagle_enabled: bool,
+    hop_limit: Option<u8>,
+    flush_waits_for_ack: bool,
+    shutdown_timeout: Option<std::time::Duration>,
+    congestion_control: CongestionControl,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            send_buffer_size: DEFAULT_TCP_SEND_BUFFER_SIZE,
+            recv_buffer_size: DEFAULT_TCP_RECV_BUFFER_SIZE,
+            min_buffer_size: DEFAULT_TCP_MIN_BUFFER_SIZE,
+            socket_buffer_size: DEFAULT_TCP_SOCKET_BUFFER_SIZE,
+            keep_alive: Some(std::time::Duration::from_secs(28)),
+            // FIXME: It should follow system's setting. 7200 is Linux's default.
+            idle_timeout: Some(std::time::Duration::from_secs(7200)),
+            // smoltcp's default ACK delay.
+            ack_delay: Some(std::time::Duration::from_millis(10)),
+            nagle_enabled: true,
+            hop_limit: None,
+            flush_waits_for_ack: false,
+            shutdown_timeout: Some(std::time::Duration::from_secs(10)),
+            congestion_control: default_congestion_control(),
+        }
+    }
+}
+
+/// The best algorithm enabled, as smoltcp picks it: CUBIC, then Reno.
+#[allow(unreachable_code)]
+fn default_congestion_control() -> CongestionControl {
+    #[cfg(feature = "tcp-cubic")]
+    return CongestionControl::Cubic;
+    #[cfg(feature = "tcp-reno")]
+    return CongestionControl::Reno;
+    CongestionControl::None
+}
+
+impl TcpConfig {
+    /// Ceiling of the send buffer of the stream, the smoltcp socket behind
+    /// it takes at most the [socket buffer size](Self::socket_buffer_size).
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = size;
+        self
+    }
+
+    /// Ceiling of the receive buffer of the stream, the smoltcp socket in
+    /// front of it takes at most the [socket buffer size](Self::socket_buffer_size).
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Size of the buffers handed out once the memory budget is exhausted.
+    pub fn min_buffer_size(mut self, size: usize) -> Self {
+        self.min_buffer_size = size;
+        self
+    }
+
+    /// Size of each smoltcp socket buffer, allocated for the whole connection.
+    ///
+    /// It bounds the receive window advertised to the peer and the data in
+    /// flight, it is capped by the send and receive buffer sizes, and at 1 GiB.
+    pub fn socket_buffer_size(mut self, size: usize) -> Self {
+        self.socket_buffer_size = size.min(MAX_TCP_SOCKET_BUFFER_SIZE);
+        self
+    }
+
+    /// Interval of keep-alive ACKs sent on an idle connection, `None` disables them.
+    pub fn keep_alive(mut self, interval: Option<std::time::Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Abort the connection if the peer stays silent for this long, `None` never aborts.
+    pub fn idle_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Delay before sending a pure ACK, `None` acknowledges immediately.
+    pub fn ack_delay(mut self, delay: Option<std::time::Duration>) -> Self {
+        self.ack_delay = delay;
+        self
+    }
+
+    /// Hold back small segments while data is unacknowledged (Nagle's
+    /// algorithm), overridable per stream with [`TcpStream::set_nodelay`].
+    pub fn nagle_enabled(mut self, enabled: bool) -> Self {
+        self.nagle_enabled = enabled;
+        self
+    }
+
+    /// TTL (IPv4) or hop limit (IPv6) of outgoing packets, `None` uses smoltcp's default (64).
+    ///
+    /// A hop limit of zero is treated as `None`.
+    pub fn hop_limit(mut self, hop_limit: Option<u8>) -> Self {
+        self.hop_limit = hop_limit.filter(|&hop_limit| hop_limit != 0);
+        self
+    }
+
+    /// Have [`poll_flush`](AsyncWrite::poll_flush) wait until the peer has
+    /// acknowledged everything written, instead of returning once the data
+    /// has been handed to the smoltcp socket.
+    pub fn flush_waits_for_ack(mut self, enabled: bool) -> Self {
+        self.flush_waits_for_ack = enabled;
+        self
+    }
+
+    /// How long [`poll_shutdown`](AsyncWrite::poll_shutdown) waits for the peer
+    /// to acknowledge the FIN, `None` waits forever.
+    ///
+    /// The FIN is still retransmitted after the deadline, the write half just
+    /// isn't waited on anymore.
+    pub fn shutdown_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Congestion control algorithm of the send side, overridable per stream
+    /// with [`TcpStream::set_congestion_control`].
+    ///
+    /// The algorithms are enabled by the `tcp-reno` and `tcp-cubic` cargo
+    /// features, the default is CUBIC if enabled, then Reno, then none.
+    pub fn congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+        self.congestion_control = congestion_control;
+        self
+    }
+
+    /// Receive buffer size of the smoltcp socket, which its window is scaled for.
+    fn socket_recv_size(&self) -> usize {
+        self.socket_buffer_size.min(self.recv_buffer_size)
+    }
+
+    fn create_socket(&self, memory: &TcpMemory) -> TcpSocket<'static> {
+        let recv_size = self.socket_recv_size();
+        let send_size = self.socket_buffer_size.min(self.send_buffer_size);
+        let recv_buffer_size = memory.reserve(self.min_buffer_size.min(recv_size), recv_size);
+        let send_buffer_size = memory.reserve(self.min_buffer_size.min(send_size), send_size);
+        let mut socket = TcpSocket::new(
+            TcpSocketBuffer::new(vec![0u8; recv_buffer_size]),
+            TcpSocketBuffer::new(vec![0u8; send_buffer_size]),
+        );
+        socket.set_keep_alive(self.keep_alive.map(Duration::from));
+        socket.set_timeout(self.idle_timeout.map(Duration::from));
+        socket.set_ack_delay(self.ack_delay.map(Duration::from));
+        socket.set_nagle_enabled(self.nagle_enabled);
+        socket.set_hop_limit(self.hop_limit);
+        socket
+    }
+
+    fn create_control(&self, memory: &Arc<TcpMemory>) -> TcpSocketControl {
+        TcpSocketControl {
+            socket: None,
+            stats: Arc::new(SpinMutex::new(TcpFlowStats::new())),
+            send_buffer: AdaptiveBuffer::new(
+                self.min_buffer_size,
+                self.send_buffer_size,
+                memory.clone(),
+            ),
+            send_waker: None,
+            recv_buffer: Some(AdaptiveBuffer::new(
+                self.min_buffer_size,
+                self.recv_buffer_size,
+                memory.clone(),
+            )),
+            recv_waker: None,
+            recv_state: TcpSocketState::Normal,
+            send_state: TcpSocketState::Normal,
+            tcp_state: TcpState::Listen,
+            socket_send_queue: 0,
+            socket_recv_queue: 0,
+            close_reason: None,
+            shutdown_timer: None,
+            linger: None,
+            linger_deadline: None,
+            aborted: false,
+            options_changed: false,
+            congestion_control: self.congestion_control,
+            idle_timeout: self.idle_timeout,
+            keep_alive: self.keep_alive,
+            ack_delay: self.ack_delay,
+            nagle_enabled: self.nagle_enabled,
+            hop_limit: self.hop_limit,
+            read_timeout: None,
+            write_timeout: None,
+            reset_on_timeout: false,
+            read_timer: None,
+            write_timer: None,
+        }
+    }
+}
+
+/// Why a connection has been closed, returned by [`TcpStream::close_reason`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TcpCloseReason {
+    /// The peer has closed its write half with a FIN.
+    Fin,
+    /// The peer has reset the connection with a RST.
+    Reset,
+    /// The peer stopped answering, e.g. to keep-alives, within the
+    /// [idle timeout](TcpConfig::idle_timeout).
+    TimedOut,
+    /// The connection has been reset locally by [`TcpStream::abort`] or its linger time.
+    Aborted,
+}
+
+impl TcpCloseReason {
+    /// The error reported by reads and writes, `None` for a graceful close.
+    fn error(self) -> Option<std::io::Error> {
+        let kind = match self {
+            TcpCloseReason::Fin => return None,
+            TcpCloseReason::Reset => std::io::ErrorKind::ConnectionReset,
+            TcpCloseReason::TimedOut => std::io::ErrorKind::TimedOut,
+            TcpCloseReason::Aborted => std::io::ErrorKind::ConnectionAborted,
+        };
+        Some(kind.into())
+    }
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum TcpSocketState {
@@ -46,156 +298,858 @@ enum TcpSocketState {
     Closed,
 }
 
+/// State shared between a [`TcpStream`] and the runner servicing its socket.
+///
+/// The stream doesn't read or write the smoltcp socket buffers: smoltcp can't
+/// resize them, so they are kept at a fixed window while the adaptive buffers
+/// here absorb the rest, and the runner copies between the two.
 struct TcpSocketControl {
-    send_buffer: RingBuffer<'static, u8>,
+    /// Handle in the runner's [`SocketSet`], `None` until the runner has added
+    /// the socket and once it has removed it.
+    socket: Option<SocketHandle>,
+    stats: Arc<SpinMutex<TcpFlowStats>>,
+    /// Written by the stream, moved to the socket by the runner.
+    send_buffer: AdaptiveBuffer,
     send_waker: Option<Waker>,
-    recv_buffer: RingBuffer<'static, u8>,
+    /// Filled from the socket by the runner, read by the stream. `None` while
+    /// the stream has it lent to [`AsyncBufRead::poll_fill_buf`], the runner
+    /// leaves what is received in the socket meanwhile.
+    recv_buffer: Option<AdaptiveBuffer>,
     recv_waker: Option<Waker>,
     recv_state: TcpSocketState,
     send_state: TcpSocketState,
+    /// Socket state, as of the last time the runner serviced it.
+    tcp_state: TcpState,
+    /// Bytes in the socket's send buffer, i.e. not acknowledged yet, as of the
+    /// last time the runner serviced it.
+    socket_send_queue: usize,
+    /// Bytes in the socket's receive buffer, as of the last time the runner
+    /// serviced it.
+    socket_recv_queue: usize,
+    close_reason: Option<TcpCloseReason>,
+    /// Deadline of a [`poll_shutdown`](AsyncWrite::poll_shutdown) waiting for the FIN's ACK.
+    shutdown_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// See [`TcpStream::set_linger`].
+    linger: Option<std::time::Duration>,
+    /// When the runner resets the connection if it hasn't closed gracefully,
+    /// set once the stream is dropped.
+    linger_deadline: Option<std::time::Instant>,
+    /// The stream has reset the connection, the runner aborts the socket on
+    /// its next loop.
+    aborted: bool,
+    /// An option has been set on the stream since the runner last applied them.
+    options_changed: bool,
+    /// See [`TcpStream::set_congestion_control`], applied once the socket is added.
+    congestion_control: CongestionControl,
+    /// See [`TcpStream::set_idle_timeout`], applied once the socket is added.
+    idle_timeout: Option<std::time::Duration>,
+    /// See [`TcpStream::set_keepalive`], applied once the socket is added.
+    keep_alive: Option<std::time::Duration>,
+    /// See [`TcpStream::set_ack_delay`], applied once the socket is added.
+    ack_delay: Option<std::time::Duration>,
+    /// See [`TcpStream::set_nodelay`], applied once the socket is added.
+    nagle_enabled: bool,
+    /// See [`TcpStream::set_hop_limit`], applied once the socket is added.
+    hop_limit: Option<u8>,
+    /// See [`TcpStream::set_read_timeout`].
+    read_timeout: Option<std::time::Duration>,
+    /// See [`TcpStream::set_write_timeout`].
+    write_timeout: Option<std::time::Duration>,
+    /// See [`TcpStream::set_reset_on_timeout`].
+    reset_on_timeout: bool,
+    /// Deadline of the read waiting for data.
+    read_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Deadline of the write waiting for room in the buffer, or of the flush
+    /// waiting for the ACK.
+    write_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl TcpSocketControl {
+    /// Whether a new connection may take over the tuple of this one.
+    fn is_reusable(&self) -> bool {
+        matches!(self.tcp_state, TcpState::TimeWait | TcpState::Closed)
+    }
+
+    /// Record why the connection is closed, a reset or timeout overrides a FIN
+    /// received earlier.
+    fn set_close_reason(&mut self, reason: TcpCloseReason) {
+        if matches!(self.close_reason, None | Some(TcpCloseReason::Fin)) {
+            self.close_reason = Some(reason);
+        }
+    }
+
+    /// Apply the options set on the stream.
+    fn apply_options(&self, socket: &mut TcpSocket<'static>) {
+        socket.set_congestion_control(self.congestion_control);
+        socket.set_timeout(self.idle_timeout.map(Duration::from));
+        socket.set_keep_alive(self.keep_alive.map(Duration::from));
+        socket.set_ack_delay(self.ack_delay.map(Duration::from));
+        socket.set_nagle_enabled(self.nagle_enabled);
+        socket.set_hop_limit(self.hop_limit);
+    }
+
+    /// The error of an operation on a closed half.
+    fn closed_error(&self) -> std::io::Error {
+        self.close_reason
+            .and_then(TcpCloseReason::error)
+            .unwrap_or_else(|| std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    /// Close both halves and wake up the stream, used when the socket will never be serviced.
+    fn close(&mut self) {
+        self.send_state = TcpSocketState::Closed;
+        self.recv_state = TcpSocketState::Closed;
+
+        if let Some(waker) = self.send_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The packets a pending connection has been opened with.
+enum TcpHandshake {
+    /// The client's SYN, not answered yet.
+    Syn(AnyIpPktFrame),
+    /// The client's final ACK validating a SYN cookie, and the SYN rebuilt
+    /// from it for the listening socket.
+    Cookie {
+        ack: AnyIpPktFrame,
+        syn: AnyIpPktFrame,
+        cookie: SynCookie,
+    },
+}
+
+impl TcpHandshake {
+    /// The packet a rejection answers to.
+    fn trigger(&self) -> &[u8] {
+        match self {
+            TcpHandshake::Syn(syn) => syn,
+            TcpHandshake::Cookie { ack, .. } => ack,
+        }
+    }
+}
+
+/// The decision on a [`PendingTcpStream`], `id` tells it from a connection
+/// reset by the accept timeout that the tuple has been reused by since.
+enum TcpVerdict {
+    Accept {
+        id: u64,
+        handshake: TcpHandshake,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        control: SharedControl,
+    },
+    Reject {
+        id: u64,
+        handshake: TcpHandshake,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        reject: TcpReject,
+    },
 }
 
 struct TcpSocketCreation {
+    tuple: TcpTuple,
     control: SharedControl,
     socket: TcpSocket<'static>,
+    /// Whether the socket connects to the TUN side instead of answering a SYN.
+    connect: bool,
+}
+
+/// A request of a [`TcpConnector`] to open a connection.
+struct TcpConnect {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    stream_tx: oneshot::Sender<std::io::Result<TcpStream>>,
 }
 
 type SharedNotify = Arc<Notify>;
 type SharedControl = Arc<SpinMutex<TcpSocketControl>>;
+/// Sockets the runner has to service on its next loop.
+type DirtySockets = Arc<SpinMutex<HashSet<SocketHandle>>>;
 
-struct TcpListenerRunner;
+/// Marks its socket dirty when smoltcp wakes it up.
+struct DirtySocketWaker {
+    handle: SocketHandle,
+    dirty_sockets: DirtySockets,
+}
 
-impl TcpListenerRunner {
-    fn create(
-        device: VirtualDevice,
-        iface: Interface,
-        iface_ingress_tx: UnboundedSender<Vec<u8>>,
-        iface_ingress_tx_avail: Arc<AtomicBool>,
-        tcp_rx: Receiver<AnyIpPktFrame>,
-        stream_tx: UnboundedSender<TcpStream>,
-        sockets: HashMap<SocketHandle, SharedControl>,
-    ) -> Runner {
-        Runner::new(async move {
-            let notify = Arc::new(Notify::new());
-            let (socket_tx, socket_rx) = unbounded_channel::<TcpSocketCreation>();
-            let res = tokio::select! {
-                v = Self::handle_packet(notify.clone(), iface_ingress_tx, iface_ingress_tx_avail.clone(), tcp_rx, stream_tx, socket_tx) => v,
-                v = Self::handle_socket(notify, device, iface, iface_ingress_tx_avail, sockets, socket_rx) => v,
-            };
-            res?;
-            trace!("VirtDevice::poll thread exited");
-            Ok(())
-        })
+impl Wake for DirtySocketWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.dirty_sockets.lock().insert(self.handle);
+    }
+}
+
+/// Wait on `slot`, waking up the task it replaces so that it won't be lost.
+fn register_waker(slot: &mut Option<Waker>, cx: &Context<'_>) {
+    if let Some(old_waker) = slot.replace(cx.waker().clone()) {
+        if !old_waker.will_wake(cx.waker()) {
+            old_waker.wake();
+        }
+    }
+}
+
+/// Poll the deadline of an operation waiting for up to `timeout`, returns
+/// whether it has passed.
+///
+/// The deadline is armed when the operation first has to wait, and kept
+/// until it completes, which clears `timer`.
+fn poll_timer(
+    timer: &mut Option<Pin<Box<tokio::time::Sleep>>>,
+    timeout: Option<std::time::Duration>,
+    cx: &mut Context<'_>,
+) -> bool {
+    let Some(timeout) = timeout else {
+        *timer = None;
+        return false;
+    };
+
+    let sleep = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    if sleep.as_mut().poll(cx).is_ready() {
+        *timer = None;
+        return true;
+    }
+    false
+}
+
+/// Connection key, (src, dst) of packets sent by the TUN side.
+type TcpTuple = (SocketAddr, SocketAddr);
+
+enum TcpConnection {
+    /// The SYN is held by a [`PendingTcpStream`] waiting for a verdict.
+    Pending(u64),
+    /// The socket has been handed over to the interface.
+    Accepted(SharedControl),
+    /// The socket has been opened by a [`TcpConnector`], it isn't counted by
+    /// the limiter.
+    Connected(SharedControl),
+}
+
+impl TcpConnection {
+    fn control(&self) -> Option<&SharedControl> {
+        match self {
+            TcpConnection::Pending(_) => None,
+            TcpConnection::Accepted(control) | TcpConnection::Connected(control) => Some(control),
+        }
     }
+}
+
+/// When a pending connection is reset if the application hasn't decided on it.
+struct TcpAcceptDeadline {
+    deadline: tokio::time::Instant,
+    tuple: TcpTuple,
+    id: u64,
+    /// The packet the reset answers to.
+    trigger: AnyIpPktFrame,
+    backlog_slot: Arc<TcpBacklogSlot>,
+}
+
+struct TcpPacketHandler {
+    config: Arc<TcpConfig>,
+    notify: SharedNotify,
+    iface_ingress_tx: UnboundedSender<Vec<u8>>,
+    iface_ingress_tx_avail: Arc<AtomicBool>,
+    stack_tx: Sender<AnyIpPktFrame>,
+    bindings: Arc<TcpBindings>,
+    socket_tx: UnboundedSender<TcpSocketCreation>,
+    dirty_sockets: DirtySockets,
+    verdict_tx: UnboundedSender<TcpVerdict>,
+    connections: HashMap<TcpTuple, TcpConnection>,
+    limiter: Arc<TcpLimiter>,
+    syn_cookies: Option<SynCookies>,
+    next_pending_id: u64,
+    accept_deadlines: VecDeque<TcpAcceptDeadline>,
+    /// Statistics of the runner's connections, where their path MTU is kept.
+    monitor: Arc<TcpFlowMonitor>,
+    /// MTU of the TUN interface.
+    mtu: usize,
+}
 
-    async fn handle_packet(
-        notify: SharedNotify,
-        iface_ingress_tx: UnboundedSender<Vec<u8>>,
-        iface_ingress_tx_avail: Arc<AtomicBool>,
+impl TcpPacketHandler {
+    async fn run(
+        mut self,
         mut tcp_rx: Receiver<AnyIpPktFrame>,
-        stream_tx: UnboundedSender<TcpStream>,
-        socket_tx: UnboundedSender<TcpSocketCreation>,
+        mut verdict_rx: UnboundedReceiver<TcpVerdict>,
+        mut closed_rx: UnboundedReceiver<(TcpTuple, SharedControl)>,
+        mut connect_rx: UnboundedReceiver<TcpConnect>,
     ) -> std::io::Result<()> {
-        while let Some(frame) = tcp_rx.recv().await {
-            let packet = match IpPacket::new_checked(frame.as_slice()) {
-                Ok(p) => p,
-                Err(err) => {
-                    error!("invalid TCP IP packet: {:?}", err,);
-                    continue;
-                }
-            };
+        loop {
+            let accept_deadline = self
+                .accept_deadlines
+                .front()
+                .map(|deadline| deadline.deadline);
+            tokio::select! {
+                frame = tcp_rx.recv() => match frame {
+                    Some(frame) => self.handle_frame(frame)?,
+                    None => break,
+                },
+                Some(verdict) = verdict_rx.recv() => self.handle_verdict(verdict)?,
+                Some(connect) = connect_rx.recv() => self.handle_connect(connect)?,
+                Some((tuple, control)) = closed_rx.recv() => self.handle_closed(tuple, control),
+                _ = tokio::time::sleep_until(accept_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if accept_deadline.is_some() => self.handle_accept_timeout(),
+            }
+        }
+        Ok(())
+    }
 
-            // Specially handle icmp packet by TCP interface.
-            if matches!(packet.protocol(), IpProtocol::Icmp | IpProtocol::Icmpv6) {
-                iface_ingress_tx
-                    .send(frame)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
-                iface_ingress_tx_avail.store(true, Ordering::Release);
-                notify.notify_one();
-                continue;
+    fn handle_frame(&mut self, mut frame: AnyIpPktFrame) -> std::io::Result<()> {
+        let packet = match IpPacket::new_checked(frame.as_slice()) {
+            Ok(p) => p,
+            Err(err) => {
+                error!("invalid TCP IP packet: {:?}", err,);
+                return Ok(());
+            }
+        };
+
+        // Specially handle icmp packet by TCP interface.
+        if matches!(packet.protocol(), IpProtocol::Icmp | IpProtocol::Icmpv6) {
+            if let Some((tuple, seq_number, mtu)) = pmtu::too_big(&frame) {
+                self.handle_too_big(tuple, seq_number, mtu);
+                return Ok(());
             }
+            return self.ingress(frame);
+        }
 
-            let src_ip = packet.src_addr();
-            let dst_ip = packet.dst_addr();
-            let payload = packet.payload();
+        let src_ip = packet.src_addr();
+        let dst_ip = packet.dst_addr();
+        let payload = packet.payload();
 
-            let packet = match TcpPacket::new_checked(payload) {
-                Ok(p) => p,
-                Err(err) => {
-                    error!("invalid TCP err: {err}, src_ip: {src_ip}, dst_ip: {dst_ip}, payload: {payload:?}");
-                    continue;
+        let packet = match TcpPacket::new_checked(payload) {
+            Ok(p) => p,
+            Err(err) => {
+                error!("invalid TCP err: {err}, src_ip: {src_ip}, dst_ip: {dst_ip}, payload: {payload:?}");
+                return Ok(());
+            }
+        };
+        let src_port = packet.src_port();
+        let dst_port = packet.dst_port();
+        let (syn, ack) = (packet.syn(), packet.ack());
+
+        let src_addr = SocketAddr::new(src_ip, src_port);
+        let dst_addr = SocketAddr::new(dst_ip, dst_port);
+        let tuple = (src_addr, dst_addr);
+
+        // Keep the SYN as sent, before its MSS is clamped.
+        let syn_info = if syn && !ack {
+            TcpSynInfo::parse(&frame)
+        } else {
+            None
+        };
+        pmtu::clamp_mss(&mut frame, self.mtu);
+
+        match self.connections.get(&tuple) {
+            // Nothing is answered until the application decides, including
+            // retransmitted SYNs.
+            Some(TcpConnection::Pending(_)) => {
+                trace!("held TCP packet for pending {} <-> {}", src_addr, dst_addr);
+                return Ok(());
+            }
+            // A new SYN may only reuse the tuple once the old connection has
+            // reached TIME-WAIT, otherwise it belongs to the existing socket.
+            Some(connection) => {
+                let control = connection.control().expect("pending handled above");
+                if !(syn && !ack && control.lock().is_reusable()) {
+                    return self.ingress(frame);
+                }
+            }
+            None => {}
+        }
+
+        // TCP first handshake packet, answered statelessly with a cookie.
+        if syn && !ack {
+            if let Some(ref syn_cookies) = self.syn_cookies {
+                if let Some(syn_ack) = syn_cookies.syn_ack(&frame) {
+                    trace!("sent SYN cookie for {} <-> {}", src_addr, dst_addr);
+                    if let Err(err) = self.stack_tx.try_send(syn_ack) {
+                        trace!("dropped TCP SYN cookie: {}", err);
+                    }
                 }
+                return Ok(());
+            }
+        }
+
+        // The final ACK of a cookie handshake, the connection starts from here.
+        let cookie = match self.syn_cookies {
+            Some(ref syn_cookies) if !syn => syn_cookies
+                .check(&frame)
+                .and_then(|cookie| Some((syn_cookies.syn(&frame, &cookie)?, cookie))),
+            _ => None,
+        };
+
+        // TCP first handshake packet, hold it until the application decides.
+        if (syn && !ack) || cookie.is_some() {
+            self.remove_connection(&tuple);
+            if let Err(reject) = self.limiter.admit(src_ip) {
+                trace!("refused TCP connection for {} <-> {}", src_addr, dst_addr);
+                self.reply(reject, &frame);
+                return Ok(());
+            }
+
+            trace!("pending TCP connection for {} <-> {}", src_addr, dst_addr);
+
+            let handshake = match cookie {
+                Some((syn, cookie)) => TcpHandshake::Cookie {
+                    ack: frame,
+                    syn,
+                    cookie,
+                },
+                None => TcpHandshake::Syn(frame),
             };
-            let src_port = packet.src_port();
-            let dst_port = packet.dst_port();
 
-            let src_addr = SocketAddr::new(src_ip, src_port);
-            let dst_addr = SocketAddr::new(dst_ip, dst_port);
+            let id = self.next_pending_id;
+            self.next_pending_id += 1;
+            let deadline = self
+                .limiter
+                .accept_timeout()
+                .map(|timeout| tokio::time::Instant::now() + timeout);
+            let trigger = deadline.map(|_| handshake.trigger().to_vec());
+
+            self.connections.insert(tuple, TcpConnection::Pending(id));
+            let backlog_slot = self.bindings.dispatch(PendingTcpStream {
+                id,
+                deadline,
+                backlog_slot: None,
+                src_addr,
+                dst_addr,
+                syn_info,
+                handshake: Some(handshake),
+                config: self.config.clone(),
+                memory: self.limiter.memory().clone(),
+                dirty_sockets: self.dirty_sockets.clone(),
+                notify: self.notify.clone(),
+                verdict_tx: self.verdict_tx.clone(),
+            });
+            if let (Some(deadline), Some(trigger), Some(backlog_slot)) =
+                (deadline, trigger, backlog_slot)
+            {
+                self.accept_deadlines.push_back(TcpAcceptDeadline {
+                    deadline,
+                    tuple,
+                    id,
+                    trigger,
+                    backlog_slot,
+                });
+            }
+            return Ok(());
+        }
 
-            // TCP first handshake packet, create a new Connection
-            if packet.syn() && !packet.ack() {
-                let mut socket = TcpSocket::new(
-                    TcpSocketBuffer::new(vec![0u8; DEFAULT_TCP_RECV_BUFFER_SIZE as usize]),
-                    TcpSocketBuffer::new(vec![0u8; DEFAULT_TCP_SEND_BUFFER_SIZE as usize]),
-                );
-                socket.set_keep_alive(Some(Duration::from_secs(28)));
-                // FIXME: It should follow system's setting. 7200 is Linux's default.
-                socket.set_timeout(Some(Duration::from_secs(7200)));
-                // NO ACK delay
-                // socket.set_ack_delay(None);
+        // Pipeline tcp stream packet, the interface resets unknown connections.
+        self.ingress(frame)
+    }
 
+    fn handle_verdict(&mut self, verdict: TcpVerdict) -> std::io::Result<()> {
+        match verdict {
+            TcpVerdict::Accept {
+                id,
+                handshake,
+                src_addr,
+                dst_addr,
+                control,
+            } => {
+                let tuple = (src_addr, dst_addr);
+                if !self.is_pending(&tuple, id) {
+                    trace!(
+                        "accepted TCP connection {} <-> {} too late",
+                        src_addr,
+                        dst_addr
+                    );
+                    let mut control = control.lock();
+                    control.set_close_reason(TcpCloseReason::TimedOut);
+                    control.close();
+                    return Ok(());
+                }
+
+                let memory = self.limiter.memory().clone();
+                let mut socket = self.config.create_socket(&memory);
                 if let Err(err) = socket.listen(dst_addr) {
                     error!("listen error: {:?}", err);
-                    continue;
+                    memory.release(socket.recv_capacity() + socket.send_capacity());
+                    self.remove_connection(&tuple);
+                    control.lock().close();
+                    return Ok(());
                 }
 
                 trace!("created TCP connection for {} <-> {}", src_addr, dst_addr);
 
-                let control = Arc::new(SpinMutex::new(TcpSocketControl {
-                    send_buffer: RingBuffer::new(vec![0u8; DEFAULT_TCP_SEND_BUFFER_SIZE as usize]),
-                    send_waker: None,
-                    recv_buffer: RingBuffer::new(vec![0u8; DEFAULT_TCP_RECV_BUFFER_SIZE as usize]),
-                    recv_waker: None,
-                    recv_state: TcpSocketState::Normal,
-                    send_state: TcpSocketState::Normal,
-                }));
-
-                stream_tx
-                    .send(TcpStream {
-                        src_addr,
-                        dst_addr,
-                        notify: notify.clone(),
-                        control: control.clone(),
+                self.connections
+                    .insert(tuple, TcpConnection::Accepted(control.clone()));
+                self.socket_tx
+                    .send(TcpSocketCreation {
+                        tuple,
+                        control,
+                        socket,
+                        connect: false,
                     })
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
-                socket_tx
-                    .send(TcpSocketCreation { control, socket })
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+
+                // Replay the held SYN, the new socket answers it with SYN-ACK.
+                let syn = match handshake {
+                    TcpHandshake::Syn(syn) => syn,
+                    TcpHandshake::Cookie { ack, syn, cookie } => {
+                        // The client already got its SYN-ACK, the socket's one is
+                        // swallowed and the ACK replayed once it has been seen.
+                        if let Some(ref syn_cookies) = self.syn_cookies {
+                            let window_shift = syn_cookies.window_shift(&cookie);
+                            syn_cookies
+                                .translator()
+                                .register(tuple, &cookie, window_shift, ack);
+                        }
+                        syn
+                    }
+                };
+                self.ingress(syn)
+            }
+            TcpVerdict::Reject {
+                id,
+                handshake,
+                src_addr,
+                dst_addr,
+                reject,
+            } => {
+                let tuple = (src_addr, dst_addr);
+                if self.is_pending(&tuple, id) {
+                    self.remove_connection(&tuple);
+                    self.reply(reject, handshake.trigger());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Lower the path MTU of the connection a segment was too big for.
+    fn handle_too_big(&mut self, tuple: TcpTuple, seq_number: u32, mtu: usize) {
+        if mtu >= self.mtu {
+            return;
+        }
+        // The quoted segment is in the client's sequence space.
+        let seq_number = match self.syn_cookies {
+            Some(ref syn_cookies) => syn_cookies.translator().socket_seq(&tuple, seq_number),
+            None => seq_number,
+        };
+        if self.monitor.lower_path_mtu(&tuple, mtu, seq_number) {
+            trace!("TCP path MTU {} for {} <-> {}", mtu, tuple.0, tuple.1);
+        }
+    }
+
+    /// Whether the connection of a verdict is still waiting for it.
+    fn is_pending(&self, tuple: &TcpTuple, id: u64) -> bool {
+        matches!(self.connections.get(tuple), Some(TcpConnection::Pending(pending)) if *pending == id)
+    }
+
+    /// Reset the pending connections the application hasn't decided on in time.
+    fn handle_accept_timeout(&mut self) {
+        let now = tokio::time::Instant::now();
+        while let Some(front) = self.accept_deadlines.front() {
+            if front.deadline > now {
+                break;
             }
+            let expired = self.accept_deadlines.pop_front().expect("front");
+            let tuple = expired.tuple;
+            if self.is_pending(&tuple, expired.id) {
+                trace!("TCP accept timed out for {} <-> {}", tuple.0, tuple.1);
+                self.limiter.accept_timed_out();
+                self.remove_connection(&tuple);
+                self.reply(TcpReject::Reset, &expired.trigger);
+                // Free its place in the backlog before the listener skips it.
+                expired.backlog_slot.release();
+            }
+        }
+    }
 
-            // Pipeline tcp stream packet
-            iface_ingress_tx
-                .send(frame)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
-            iface_ingress_tx_avail.store(true, Ordering::Release);
-            notify.notify_one();
+    fn handle_connect(&mut self, connect: TcpConnect) -> std::io::Result<()> {
+        let TcpConnect {
+            src_addr,
+            dst_addr,
+            stream_tx,
+        } = connect;
+        let tuple = (src_addr, dst_addr);
+
+        if let Some(connection) = self.connections.get(&tuple) {
+            let reusable = connection
+                .control()
+                .is_some_and(|control| control.lock().is_reusable());
+            if !reusable {
+                let _ = stream_tx.send(Err(std::io::ErrorKind::AddrInUse.into()));
+                return Ok(());
+            }
+            self.remove_connection(&tuple);
         }
+
+        trace!(
+            "connecting TCP connection for {} <-> {}",
+            src_addr,
+            dst_addr
+        );
+
+        let memory = self.limiter.memory();
+        let control = Arc::new(SpinMutex::new(self.config.create_control(memory)));
+        let socket = self.config.create_socket(memory);
+        self.connections
+            .insert(tuple, TcpConnection::Connected(control.clone()));
+        self.socket_tx
+            .send(TcpSocketCreation {
+                tuple,
+                control: control.clone(),
+                socket,
+                connect: true,
+            })
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+        self.notify.notify_one();
+
+        // Dropped with the request if the connector has given up.
+        let _ = stream_tx.send(Ok(TcpStream {
+            src_addr,
+            dst_addr,
+            syn_info: None,
+            config: self.config.clone(),
+            notify: self.notify.clone(),
+            dirty_sockets: self.dirty_sockets.clone(),
+            control,
+            lent_recv_buffer: SpinMutex::new(None),
+        }));
         Ok(())
     }
 
-    async fn handle_socket(
-        notify: SharedNotify,
-        mut device: VirtualDevice,
-        mut iface: Interface,
-        iface_ingress_tx_avail: Arc<AtomicBool>,
-        mut sockets: HashMap<SocketHandle, SharedControl>,
-        mut socket_rx: UnboundedReceiver<TcpSocketCreation>,
-    ) -> std::io::Result<()> {
+    fn handle_closed(&mut self, tuple: TcpTuple, control: SharedControl) {
+        // The tuple may have been reused by a newer connection already.
+        let current = self
+            .connections
+            .get(&tuple)
+            .and_then(TcpConnection::control);
+        if current.is_some_and(|current| Arc::ptr_eq(current, &control)) {
+            self.remove_connection(&tuple);
+        }
+    }
+
+    fn remove_connection(&mut self, tuple: &TcpTuple) {
+        match self.connections.remove(tuple) {
+            Some(TcpConnection::Pending(_) | TcpConnection::Accepted(_)) => {
+                self.limiter.release(tuple.0.ip())
+            }
+            Some(TcpConnection::Connected(_)) | None => {}
+        }
+        if let Some(ref syn_cookies) = self.syn_cookies {
+            syn_cookies.translator().remove(tuple);
+        }
+    }
+
+    fn reply(&self, reject: TcpReject, frame: &[u8]) {
+        if let Some(reply) = reject.reply(frame) {
+            if let Err(err) = self.stack_tx.try_send(reply) {
+                trace!("dropped TCP reject reply: {}", err);
+            }
+        }
+    }
+
+    fn ingress(&self, frame: AnyIpPktFrame) -> std::io::Result<()> {
+        self.iface_ingress_tx
+            .send(frame)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+        self.iface_ingress_tx_avail.store(true, Ordering::Release);
+        self.notify.notify_one();
+        Ok(())
+    }
+}
+
+struct TcpListenerRunner;
+
+impl TcpListenerRunner {
+    fn create(
+        config: Arc<TcpConfig>,
+        shard: TcpShard,
+        stack_tx: Sender<AnyIpPktFrame>,
+        bindings: Arc<TcpBindings>,
+        limiter: Arc<TcpLimiter>,
+    ) -> Runner {
+        let TcpShard {
+            device,
+            iface,
+            iface_ingress_tx,
+            iface_ingress_tx_avail,
+            tcp_rx,
+            connect_rx,
+            syn_cookies,
+        } = shard;
+        Runner::new(async move {
+            let notify = Arc::new(Notify::new());
+            let dirty_sockets = Arc::new(SpinMutex::new(HashSet::new()));
+            let (socket_tx, socket_rx) = unbounded_channel::<TcpSocketCreation>();
+            let (verdict_tx, verdict_rx) = unbounded_channel::<TcpVerdict>();
+            let (closed_tx, closed_rx) = unbounded_channel();
+            let packet_handler = TcpPacketHandler {
+                config,
+                notify: notify.clone(),
+                iface_ingress_tx,
+                iface_ingress_tx_avail: iface_ingress_tx_avail.clone(),
+                stack_tx,
+                bindings,
+                socket_tx,
+                dirty_sockets: dirty_sockets.clone(),
+                verdict_tx,
+                connections: HashMap::new(),
+                limiter: limiter.clone(),
+                syn_cookies,
+                next_pending_id: 0,
+                accept_deadlines: VecDeque::new(),
+                monitor: device.monitor().clone(),
+                mtu: device.capabilities().max_transmission_unit,
+            };
+            let socket_runner = TcpSocketRunner {
+                notify,
+                device,
+                iface,
+                iface_ingress_tx_avail,
+                memory: limiter.memory().clone(),
+                dirty_sockets,
+                socket_rx,
+                closed_tx,
+            };
+            let res = tokio::select! {
+                v = packet_handler.run(tcp_rx, verdict_rx, closed_rx, connect_rx) => v,
+                v = socket_runner.run() => v,
+            };
+            res?;
+            trace!("VirtDevice::poll thread exited");
+            Ok(())
+        })
+    }
+}
+
+/// What a runner serves its shard of the connections with.
+struct TcpShard {
+    device: VirtualDevice,
+    iface: Interface,
+    iface_ingress_tx: UnboundedSender<Vec<u8>>,
+    iface_ingress_tx_avail: Arc<AtomicBool>,
+    tcp_rx: Receiver<AnyIpPktFrame>,
+    connect_rx: UnboundedReceiver<TcpConnect>,
+    syn_cookies: Option<SynCookies>,
+}
+
+/// Polls the interface of a runner and services the sockets added by its
+/// [`TcpPacketHandler`].
+struct TcpSocketRunner {
+    notify: SharedNotify,
+    device: VirtualDevice,
+    iface: Interface,
+    iface_ingress_tx_avail: Arc<AtomicBool>,
+    memory: Arc<TcpMemory>,
+    dirty_sockets: DirtySockets,
+    socket_rx: UnboundedReceiver<TcpSocketCreation>,
+    closed_tx: UnboundedSender<(TcpTuple, SharedControl)>,
+}
+
+impl TcpSocketRunner {
+    async fn run(self) -> std::io::Result<()> {
+        let Self {
+            notify,
+            mut device,
+            mut iface,
+            iface_ingress_tx_avail,
+            memory,
+            dirty_sockets,
+            mut socket_rx,
+            closed_tx,
+        } = self;
+        let mut sockets = HashMap::<SocketHandle, (TcpTuple, SharedControl, Waker)>::new();
         let mut socket_set = SocketSet::new(vec![]);
+        let mut tuples = HashMap::<TcpTuple, SocketHandle>::new();
+        let mut lingering = HashMap::<SocketHandle, std::time::Instant>::new();
+        let mut next_shrink = std::time::Instant::now() + BUFFER_SHRINK_IDLE;
         loop {
-            while let Ok(TcpSocketCreation { control, socket }) = socket_rx.try_recv() {
+            while let Ok(TcpSocketCreation {
+                tuple,
+                control,
+                socket,
+                connect,
+            }) = socket_rx.try_recv()
+            {
                 let handle = socket_set.add(socket);
-                sockets.insert(handle, control);
+                let stats = {
+                    let mut control = control.lock();
+                    control.socket = Some(handle);
+                    let socket = socket_set.get_mut::<TcpSocket>(handle);
+                    control.apply_options(socket);
+                    if control.aborted {
+                        socket.abort();
+                    } else if connect {
+                        // The TUN side host is the remote end of the socket.
+                        if let Err(err) = socket.connect(iface.context(), tuple.0, tuple.1) {
+                            error!("connect error: {:?}", err);
+                            control.set_close_reason(TcpCloseReason::Aborted);
+                        }
+                    }
+                    control.stats.clone()
+                };
+                let waker = Waker::from(Arc::new(DirtySocketWaker {
+                    handle,
+                    dirty_sockets: dirty_sockets.clone(),
+                }));
+                sockets.insert(handle, (tuple, control, waker));
+                dirty_sockets.lock().insert(handle);
+
+                // The tuple is reused by a new connection, drop the old socket
+                // lingering in TIME-WAIT so that it won't swallow the new SYN.
+                if let Some(old_handle) = tuples.insert(tuple, handle) {
+                    if let Some((_, old_control, _)) = sockets.remove(&old_handle) {
+                        let mut old_control = old_control.lock();
+                        old_control.socket = None;
+                        old_control.close();
+                    }
+                    lingering.remove(&old_handle);
+                    Self::remove_socket(&mut socket_set, old_handle, &memory);
+                    trace!("reused TCP connection for {} <-> {}", tuple.0, tuple.1);
+                }
+                device.monitor().register(tuple, stats);
+            }
+
+            // Reset the connections that didn't close within their linger time.
+            let now = std::time::Instant::now();
+            lingering.retain(|&socket_handle, &mut deadline| {
+                if deadline > now {
+                    return true;
+                }
+                if let Some((_, control, _)) = sockets.get(&socket_handle) {
+                    let mut control = control.lock();
+                    if !matches!(control.send_state, TcpSocketState::Closed) {
+                        trace!("TCP linger timed out, resetting connection");
+                        socket_set.get_mut::<TcpSocket>(socket_handle).abort();
+                        control.set_close_reason(TcpCloseReason::Aborted);
+                        control.close();
+                        dirty_sockets.lock().insert(socket_handle);
+                    }
+                }
+                false
+            });
+
+            // Give back the memory of the buffers that have been idle for a while.
+            if now >= next_shrink {
+                for (_, control, _) in sockets.values() {
+                    let mut control = control.lock();
+                    control.send_buffer.shrink_if_idle(now);
+                    if let Some(ref mut recv_buffer) = control.recv_buffer {
+                        recv_buffer.shrink_if_idle(now);
+                    }
+                }
+                next_shrink = now + BUFFER_SHRINK_IDLE;
+            }
+
+            // Hand what the streams have written and requested to the sockets
+            // before the interface sends.
+            let mut dirty_handles = std::mem::take(&mut *dirty_sockets.lock());
+            for socket_handle in &dirty_handles {
+                if let Some((_, control, _)) = sockets.get(socket_handle) {
+                    let socket = socket_set.get_mut::<TcpSocket>(*socket_handle);
+                    Self::transfer(socket, &mut control.lock());
+                }
             }
 
             let before_poll = Instant::now();
@@ -207,67 +1161,77 @@ impl TcpListenerRunner {
                 trace!("VirtDevice::poll costed {}", Instant::now() - before_poll);
             }
 
-            // Check all the sockets' status
+            // Check the status of the sockets woken up by smoltcp or their stream.
             let mut sockets_to_remove = Vec::new();
+            dirty_handles.extend(std::mem::take(&mut *dirty_sockets.lock()));
 
-            for (socket_handle, control) in sockets.iter() {
-                let socket_handle = *socket_handle;
+            for socket_handle in dirty_handles {
+                let Some((_, control, waker)) = sockets.get(&socket_handle) else {
+                    continue;
+                };
                 let socket = socket_set.get_mut::<TcpSocket>(socket_handle);
                 let mut control = control.lock();
+                let aborted = Self::transfer(socket, &mut control);
+                let was_closed = control.tcp_state == TcpState::Closed;
+                control.tcp_state = socket.state();
+                control.socket_send_queue = socket.send_queue();
+                control.socket_recv_queue = socket.recv_queue();
+
+                if let Some(deadline) = control.linger_deadline.take() {
+                    lingering.insert(socket_handle, deadline);
+                }
 
-                // Remove the socket only when it is in the closed state.
+                // Remove the socket only when it is in the closed state, and the
+                // stream has read everything received before or is gone.
                 if socket.state() == TcpState::Closed {
-                    sockets_to_remove.push(socket_handle);
+                    // Find out how the connection ended, smoltcp closes the socket
+                    // silently on a RST or timeout.
+                    if !was_closed {
+                        let stats = control.stats.lock();
+                        let reason = if stats.reset_received() {
+                            TcpCloseReason::Reset
+                        } else if stats.fin_received() {
+                            TcpCloseReason::Fin
+                        } else {
+                            TcpCloseReason::TimedOut
+                        };
+                        drop(stats);
+                        control.set_close_reason(reason);
+                    }
 
-                    control.send_state = TcpSocketState::Closed;
-                    control.recv_state = TcpSocketState::Closed;
+                    // Keep the socket until the interface has sent the RST.
+                    if aborted {
+                        dirty_sockets.lock().insert(socket_handle);
+                        notify.notify_one();
+                    } else if socket.recv_queue() == 0
+                        || !matches!(control.recv_state, TcpSocketState::Normal)
+                    {
+                        sockets_to_remove.push(socket_handle);
+                        control.socket = None;
+                        control.close();
+
+                        trace!("closed TCP connection");
+                        continue;
+                    }
 
-                    if let Some(waker) = control.send_waker.take() {
-                        waker.wake();
+                    if !matches!(control.send_state, TcpSocketState::Closed) {
+                        control.send_state = TcpSocketState::Closed;
+                        if let Some(waker) = control.send_waker.take() {
+                            waker.wake();
+                        }
                     }
-                    if let Some(waker) = control.recv_waker.take() {
-                        waker.wake();
-                    }
-
-                    trace!("closed TCP connection");
-                    continue;
                 }
 
-                // SHUT_WR
-                if matches!(control.send_state, TcpSocketState::Close) {
-                    trace!("closing TCP Write Half, {:?}", socket.state());
-
-                    // Close the socket. Set to FIN state
-                    socket.close();
-                    control.send_state = TcpSocketState::Closing;
-
-                    // We can still process the pending buffer.
-                }
-
-                // Check if readable
-                let mut wake_receiver = false;
-                while socket.can_recv() && !control.recv_buffer.is_full() {
-                    let result = socket.recv(|buffer| {
-                        let n = control.recv_buffer.enqueue_slice(buffer);
-                        (n, ())
-                    });
-
-                    match result {
-                        Ok(..) => wake_receiver = true,
-                        Err(err) => {
-                            error!("socket recv error: {:?}, {:?}", err, socket.state());
-
-                            // Don't know why. Abort the connection.
-                            socket.abort();
-
-                            if matches!(control.recv_state, TcpSocketState::Normal) {
-                                control.recv_state = TcpSocketState::Closed;
-                            }
-                            wake_receiver = true;
-
-                            // The socket will be recycled in the next poll.
-                            break;
-                        }
+                // The peer has acknowledged our FIN, the write half is done.
+                if matches!(control.send_state, TcpSocketState::Closing)
+                    && matches!(
+                        socket.state(),
+                        TcpState::FinWait2 | TcpState::TimeWait | TcpState::Closed
+                    )
+                {
+                    control.send_state = TcpSocketState::Closed;
+                    if let Some(waker) = control.send_waker.take() {
+                        waker.wake();
                     }
                 }
 
@@ -275,6 +1239,7 @@ impl TcpListenerRunner {
                 // the local client have closed our receiver.
                 let states = [
                     TcpState::Listen,
+                    TcpState::SynSent,
                     TcpState::SynReceived,
                     TcpState::Established,
                     TcpState::FinWait1,
@@ -288,95 +1253,231 @@ impl TcpListenerRunner {
 
                     // Let TcpStream::poll_read returns EOF.
                     control.recv_state = TcpSocketState::Closed;
-                    wake_receiver = true;
+                    control.set_close_reason(TcpCloseReason::Fin);
                 }
 
-                if wake_receiver && control.recv_waker.is_some() {
+                // Check if readable
+                if control
+                    .recv_buffer
+                    .as_ref()
+                    .is_some_and(|recv_buffer| !recv_buffer.is_empty())
+                    || !matches!(control.recv_state, TcpSocketState::Normal)
+                {
                     if let Some(waker) = control.recv_waker.take() {
                         waker.wake();
                     }
                 }
 
                 // Check if writable
-                let mut wake_sender = false;
-                while socket.can_send() && !control.send_buffer.is_empty() {
-                    let result = socket.send(|buffer| {
-                        let n = control.send_buffer.dequeue_slice(buffer);
-                        (n, ())
-                    });
-
-                    match result {
-                        Ok(..) => wake_sender = true,
-                        Err(err) => {
-                            error!("socket send error: {:?}, {:?}", err, socket.state());
-
-                            // Don't know why. Abort the connection.
-                            socket.abort();
-
-                            if matches!(control.send_state, TcpSocketState::Normal) {
-                                control.send_state = TcpSocketState::Closed;
-                            }
-                            wake_sender = true;
-
-                            // The socket will be recycled in the next poll.
-                            break;
-                        }
-                    }
-                }
-
-                if wake_sender && control.send_waker.is_some() {
+                if !control.send_buffer.is_full()
+                    && matches!(control.send_state, TcpSocketState::Normal)
+                {
                     if let Some(waker) = control.send_waker.take() {
                         waker.wake();
                     }
                 }
+
+                // Service the socket again once smoltcp changes it.
+                socket.register_recv_waker(waker);
+                socket.register_send_waker(waker);
             }
 
             for socket_handle in sockets_to_remove {
-                sockets.remove(&socket_handle);
-                socket_set.remove(socket_handle);
-            }
-
-            if !iface_ingress_tx_avail.load(Ordering::Acquire) {
-                let next_duration = iface
-                    .poll_delay(before_poll, &socket_set)
-                    .unwrap_or(Duration::from_millis(5));
-                if next_duration != Duration::ZERO {
-                    let _ = tokio::time::timeout(
-                        tokio::time::Duration::from(next_duration),
-                        notify.notified(),
-                    )
-                    .await;
+                if let Some((tuple, control, _)) = sockets.remove(&socket_handle) {
+                    if tuples.get(&tuple) == Some(&socket_handle) {
+                        tuples.remove(&tuple);
+                        device.monitor().remove(&tuple);
+                    }
+                    let _ = closed_tx.send((tuple, control));
                 }
+                lingering.remove(&socket_handle);
+                Self::remove_socket(&mut socket_set, socket_handle, &memory);
+            }
+
+            let mut next_duration = iface
+                .poll_delay(before_poll, &socket_set)
+                .unwrap_or(Duration::from_millis(5));
+            if let Some(&deadline) = lingering.values().min() {
+                let linger_duration = Duration::from(deadline.saturating_duration_since(now));
+                next_duration = next_duration.min(linger_duration);
+            }
+            if !iface_ingress_tx_avail.load(Ordering::Acquire) && next_duration != Duration::ZERO {
+                let _ = tokio::time::timeout(
+                    tokio::time::Duration::from(next_duration),
+                    notify.notified(),
+                )
+                .await;
             }
         }
     }
+
+    /// Apply what the stream has requested and move the data between the
+    /// socket and the stream's buffers, growing the receive buffer while it
+    /// keeps filling up. Returns whether the socket has just been aborted.
+    fn transfer(socket: &mut TcpSocket<'static>, control: &mut TcpSocketControl) -> bool {
+        if control.aborted {
+            if socket.state() == TcpState::Closed {
+                return false;
+            }
+            socket.abort();
+            return true;
+        }
+
+        if std::mem::take(&mut control.options_changed) {
+            control.apply_options(socket);
+        }
+
+        // Send what the stream has written.
+        while socket.can_send() && !control.send_buffer.is_empty() {
+            let send_buffer = &mut control.send_buffer;
+            if let Err(err) = socket.send(|buffer| (send_buffer.dequeue_slice(buffer), ())) {
+                error!("socket send error: {:?}, {:?}", err, socket.state());
+                break;
+            }
+        }
+
+        // SHUT_WR, the FIN follows everything written.
+        if matches!(control.send_state, TcpSocketState::Close) && control.send_buffer.is_empty() {
+            trace!("closing TCP Write Half, {:?}", socket.state());
+
+            // Close the socket. Set to FIN state
+            socket.close();
+            control.send_state = TcpSocketState::Closing;
+        }
+
+        if matches!(control.recv_state, TcpSocketState::Close) {
+            // SHUT_RD, nobody is going to read what is received anymore.
+            if let Some(ref mut recv_buffer) = control.recv_buffer {
+                recv_buffer.clear();
+            }
+            let _ = socket.recv(|buffer| (buffer.len(), ()));
+        } else if let Some(ref mut recv_buffer) = control.recv_buffer {
+            while socket.can_recv() && (!recv_buffer.is_full() || recv_buffer.grow()) {
+                if let Err(err) = socket.recv(|buffer| (recv_buffer.enqueue_slice(buffer), ())) {
+                    error!("socket recv error: {:?}, {:?}", err, socket.state());
+                    break;
+                }
+            }
+        }
+
+        false
+    }
+
+    fn remove_socket(socket_set: &mut SocketSet, handle: SocketHandle, memory: &TcpMemory) {
+        let socket = socket_set.get::<TcpSocket>(handle);
+        memory.release(socket.recv_capacity() + socket.send_capacity());
+        socket_set.remove(handle);
+    }
+}
+
+/// The TCP settings of the [`StackBuilder`](crate::StackBuilder).
+pub(super) struct TcpSettings {
+    pub(super) config: TcpConfig,
+    pub(super) limits: TcpLimits,
+    pub(super) syn_cookies: bool,
+    pub(super) mtu: usize,
+    pub(super) unmatched: TcpReject,
 }
 
 pub struct TcpListener {
-    stream_rx: UnboundedReceiver<TcpStream>,
+    stream_rx: TcpBacklogRx,
+    handle: TcpListenerHandle,
 }
 
 impl TcpListener {
+    /// Create the listener and one runner per receiver, each with its own interface.
     pub(super) fn new(
-        tcp_rx: Receiver<AnyIpPktFrame>,
+        settings: TcpSettings,
+        tcp_rxs: Vec<Receiver<AnyIpPktFrame>>,
         stack_tx: Sender<AnyIpPktFrame>,
-    ) -> std::io::Result<(Runner, Self)> {
-        let (mut device, iface_ingress_tx, iface_ingress_tx_avail) = VirtualDevice::new(stack_tx);
-        let iface = Self::create_interface(&mut device)?;
+        shard_hasher: RandomState,
+    ) -> std::io::Result<(Vec<Runner>, Self)> {
+        let TcpSettings {
+            config,
+            limits,
+            syn_cookies,
+            mtu,
+            unmatched,
+        } = settings;
+        let config = Arc::new(config);
+        let (stream_tx, stream_rx) = backlog();
+        let limiter = Arc::new(TcpLimiter::new(limits));
+        let bindings = Arc::new(TcpBindings::new(stream_tx, unmatched, limiter.clone()));
+
+        let mut runners = Vec::with_capacity(tcp_rxs.len());
+        let mut connect_txs = Vec::with_capacity(tcp_rxs.len());
+        for tcp_rx in tcp_rxs {
+            let (connect_tx, connect_rx) = unbounded_channel();
+            connect_txs.push(connect_tx);
+            let (mut device, iface_ingress_tx, iface_ingress_tx_avail) =
+                VirtualDevice::new(stack_tx.clone(), mtu);
+            let iface = Self::create_interface(&mut device)?;
+
+            let syn_cookies = syn_cookies.then(|| {
+                let translator = Arc::new(SeqTranslator::new(iface_ingress_tx_avail.clone()));
+                device.set_translator(translator.clone());
+                SynCookies::new(mtu, config.socket_recv_size(), translator)
+            });
+
+            let shard = TcpShard {
+                device,
+                iface,
+                iface_ingress_tx,
+                iface_ingress_tx_avail,
+                tcp_rx,
+                connect_rx,
+                syn_cookies,
+            };
+            runners.push(TcpListenerRunner::create(
+                config.clone(),
+                shard,
+                stack_tx.clone(),
+                bindings.clone(),
+                limiter.clone(),
+            ));
+        }
 
-        let (stream_tx, stream_rx) = unbounded_channel();
+        let connector = TcpConnector {
+            connect_txs,
+            shard_hasher,
+        };
+        let handle = TcpListenerHandle {
+            bindings,
+            limiter,
+            connector,
+        };
+        Ok((runners, Self { stream_rx, handle }))
+    }
 
-        let runner = TcpListenerRunner::create(
-            device,
-            iface,
-            iface_ingress_tx,
-            iface_ingress_tx_avail,
-            tcp_rx,
-            stream_tx,
-            HashMap::new(),
-        );
+    /// Returns how many SYNs have been refused by the [`TcpLimits`].
+    pub fn limit_stats(&self) -> TcpLimitStats {
+        self.handle.limit_stats()
+    }
+
+    /// Returns a stream of connections whose SYN has not been answered yet.
+    ///
+    /// Unlike the [`Stream`] implementation of the listener, which accepts every
+    /// connection right away, this lets the application decide between
+    /// [`PendingTcpStream::accept`] and [`PendingTcpStream::reject`], e.g. after
+    /// dialing the upstream.
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
 
-        Ok((runner, Self { stream_rx }))
+    /// Create a listener getting the connections to the addresses in `cidr`, on
+    /// `port` or any port, see [`TcpListenerHandle::bind`].
+    pub fn bind(&self, cidr: IpCidr, port: Option<u16>) -> std::io::Result<TcpListener> {
+        self.handle.bind(cidr, port)
+    }
+
+    /// Returns a handle opening connections toward hosts on the TUN side.
+    pub fn connector(&self) -> TcpConnector {
+        self.handle.connector()
+    }
+
+    /// Returns a handle creating listeners, usable after this one is dropped.
+    pub fn handle(&self) -> TcpListenerHandle {
+        self.handle.clone()
     }
 
     fn create_interface<D>(device: &mut D) -> std::io::Result<Interface>
@@ -407,6 +1508,64 @@ impl TcpListener {
     }
 }
 
+/// Creates listeners on the stack, created by [`TcpListener::handle`].
+///
+/// Dropping a listener doesn't stop the stack: established connections keep
+/// working and new ones are refused until a listener takes its place.
+#[derive(Clone)]
+pub struct TcpListenerHandle {
+    bindings: Arc<TcpBindings>,
+    limiter: Arc<TcpLimiter>,
+    connector: TcpConnector,
+}
+
+impl TcpListenerHandle {
+    /// Create a listener replacing the one returned by the builder, once that
+    /// one has been dropped.
+    ///
+    /// Fails with [`AddrInUse`](std::io::ErrorKind::AddrInUse) while the
+    /// previous listener is alive.
+    pub fn listen(&self) -> std::io::Result<TcpListener> {
+        let (stream_tx, stream_rx) = backlog();
+        self.bindings.listen(stream_tx)?;
+        Ok(self.listener(stream_rx))
+    }
+
+    /// Create a listener getting the connections to the addresses in `cidr`, on
+    /// `port` or any port.
+    ///
+    /// The most specific listener gets a connection, binding a port wins over
+    /// a longer prefix. What no bound listener matches goes to the listener
+    /// returned by the builder, or is refused as set by
+    /// [`StackBuilder::tcp_unmatched`](crate::StackBuilder::tcp_unmatched) once
+    /// that one is dropped. Dropping a bound listener releases its binding.
+    ///
+    /// Fails with [`AddrInUse`](std::io::ErrorKind::AddrInUse) while another
+    /// listener is bound to the same `cidr` and `port`.
+    pub fn bind(&self, cidr: IpCidr, port: Option<u16>) -> std::io::Result<TcpListener> {
+        let (stream_tx, stream_rx) = backlog();
+        self.bindings.bind(cidr, port, stream_tx)?;
+        Ok(self.listener(stream_rx))
+    }
+
+    /// Returns how many SYNs have been refused by the [`TcpLimits`].
+    pub fn limit_stats(&self) -> TcpLimitStats {
+        self.limiter.stats()
+    }
+
+    /// Returns a handle opening connections toward hosts on the TUN side.
+    pub fn connector(&self) -> TcpConnector {
+        self.connector.clone()
+    }
+
+    fn listener(&self, stream_rx: TcpBacklogRx) -> TcpListener {
+        TcpListener {
+            stream_rx,
+            handle: self.clone(),
+        }
+    }
+}
+
 impl Stream for TcpListener {
     type Item = (TcpStream, SocketAddr, SocketAddr);
 
@@ -414,8 +1573,9 @@ impl Stream for TcpListener {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.stream_rx.poll_recv(cx).map(|stream| {
-            stream.map(|stream| {
+        self.stream_rx.poll_recv(cx).map(|pending| {
+            pending.map(|pending| {
+                let stream = pending.accept();
                 let local_addr = *stream.local_addr();
                 let remote_addr: SocketAddr = *stream.remote_addr();
                 (stream, local_addr, remote_addr)
@@ -424,26 +1584,265 @@ impl Stream for TcpListener {
     }
 }
 
-pub struct TcpStream {
+/// Stream of [`PendingTcpStream`]s, created by [`TcpListener::incoming`].
+pub struct Incoming<'a> {
+    listener: &'a mut TcpListener,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = PendingTcpStream;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.listener.stream_rx.poll_recv(cx)
+    }
+}
+
+/// Opens connections from the stack toward hosts on the TUN side, created by
+/// [`TcpListener::connector`].
+#[derive(Clone)]
+pub struct TcpConnector {
+    connect_txs: Vec<UnboundedSender<TcpConnect>>,
+    shard_hasher: RandomState,
+}
+
+impl TcpConnector {
+    /// Connect to `local_addr`, a host on the TUN side, from `remote_addr`.
+    ///
+    /// The addresses are named as for accepted streams, the returned stream's
+    /// [`local_addr`](TcpStream::local_addr) is the TUN side. Any address can be
+    /// connected from, the stack answers for every address.
+    ///
+    /// Resolves once the handshake is complete, fails with
+    /// [`ConnectionRefused`](std::io::ErrorKind::ConnectionRefused) if the host
+    /// answers with a RST.
+    pub async fn connect(
+        &self,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) -> std::io::Result<TcpStream> {
+        use std::io::{Error, ErrorKind};
+
+        if local_addr.is_ipv4() != remote_addr.is_ipv4()
+            || local_addr.ip().is_unspecified()
+            || remote_addr.ip().is_unspecified()
+            || local_addr.port() == 0
+            || remote_addr.port() == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid TCP connection addresses",
+            ));
+        }
+
+        let shard = tcp_shard(
+            &self.shard_hasher,
+            local_addr,
+            remote_addr,
+            self.connect_txs.len(),
+        );
+        let (stream_tx, stream_rx) = oneshot::channel();
+        self.connect_txs[shard]
+            .send(TcpConnect {
+                src_addr: local_addr,
+                dst_addr: remote_addr,
+                stream_tx,
+            })
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?;
+        let stream = stream_rx
+            .await
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))??;
+
+        // The runner wakes the writer up on every state change.
+        std::future::poll_fn(|cx| {
+            let mut control = stream.control.lock();
+            if matches!(control.tcp_state, TcpState::Listen | TcpState::SynSent)
+                && matches!(control.send_state, TcpSocketState::Normal)
+            {
+                register_waker(&mut control.send_waker, cx);
+                return Poll::Pending;
+            }
+            Poll::Ready(())
+        })
+        .await;
+        let control = stream.control.lock();
+        if !matches!(control.send_state, TcpSocketState::Normal) {
+            return Err(match control.close_reason {
+                Some(TcpCloseReason::Reset) => ErrorKind::ConnectionRefused.into(),
+                _ => control.closed_error(),
+            });
+        }
+        drop(control);
+        Ok(stream)
+    }
+}
+
+/// How a [`PendingTcpStream`] is refused.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TcpReject {
+    /// Answer with a TCP RST, the client sees "connection refused".
+    Reset,
+    /// Answer with an ICMP network unreachable error.
+    NetworkUnreachable,
+    /// Answer with an ICMP host unreachable error.
+    HostUnreachable,
+    /// Answer with an ICMP port unreachable error.
+    PortUnreachable,
+    /// Answer with an ICMP administratively prohibited error.
+    Prohibited,
+    /// Drop the SYN silently, the client times out.
+    Drop,
+}
+
+impl TcpReject {
+    fn reply(self, syn: &[u8]) -> Option<AnyIpPktFrame> {
+        let reason = match self {
+            TcpReject::Reset => return tcp_reset_reply(syn),
+            TcpReject::Drop => return None,
+            TcpReject::NetworkUnreachable => IcmpUnreachable::Network,
+            TcpReject::HostUnreachable => IcmpUnreachable::Host,
+            TcpReject::PortUnreachable => IcmpUnreachable::Port,
+            TcpReject::Prohibited => IcmpUnreachable::Prohibited,
+        };
+        icmp_unreachable_reply(syn, reason)
+    }
+}
+
+/// A TCP connection whose SYN has been received but not answered yet.
+///
+/// Dropping it without a decision rejects the connection with [`TcpReject::Reset`].
+pub struct PendingTcpStream {
+    id: u64,
+    deadline: Option<tokio::time::Instant>,
+    backlog_slot: Option<Arc<TcpBacklogSlot>>,
     src_addr: SocketAddr,
     dst_addr: SocketAddr,
+    syn_info: Option<TcpSynInfo>,
+    handshake: Option<TcpHandshake>,
+    config: Arc<TcpConfig>,
+    memory: Arc<TcpMemory>,
+    dirty_sockets: DirtySockets,
     notify: SharedNotify,
-    control: SharedControl,
+    verdict_tx: UnboundedSender<TcpVerdict>,
 }
 
-impl Drop for TcpStream {
+impl Drop for PendingTcpStream {
     fn drop(&mut self) {
-        let mut control = self.control.lock();
+        if let Some(handshake) = self.handshake.take() {
+            let _ = self.verdict_tx.send(TcpVerdict::Reject {
+                id: self.id,
+                handshake,
+                src_addr: self.src_addr,
+                dst_addr: self.dst_addr,
+                reject: TcpReject::Reset,
+            });
+        }
+    }
+}
 
-        if matches!(control.recv_state, TcpSocketState::Normal) {
-            control.recv_state = TcpSocketState::Close;
+impl PendingTcpStream {
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.src_addr
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.dst_addr
+    }
+
+    /// Returns the client's SYN, `None` if it was answered with a
+    /// [SYN cookie](crate::StackBuilder::tcp_syn_cookies), which doesn't keep it.
+    pub fn syn_info(&self) -> Option<&TcpSynInfo> {
+        self.syn_info.as_ref()
+    }
+
+    pub(crate) fn set_backlog_slot(&mut self, slot: Arc<TcpBacklogSlot>) {
+        self.backlog_slot = Some(slot);
+    }
+
+    pub(crate) fn take_backlog_slot(&mut self) -> Option<Arc<TcpBacklogSlot>> {
+        self.backlog_slot.take()
+    }
+
+    /// Whether the connection has been reset by the
+    /// [accept timeout](crate::TcpLimits::accept_timeout).
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= tokio::time::Instant::now())
+    }
+
+    /// Answer the SYN with a SYN-ACK and return the stream of the connection.
+    ///
+    /// The stream fails with [`TimedOut`](std::io::ErrorKind::TimedOut) if
+    /// the connection [has expired](Self::is_expired).
+    pub fn accept(mut self) -> TcpStream {
+        let control = Arc::new(SpinMutex::new(self.config.create_control(&self.memory)));
+
+        if let Some(handshake) = self.handshake.take() {
+            let verdict = TcpVerdict::Accept {
+                id: self.id,
+                handshake,
+                src_addr: self.src_addr,
+                dst_addr: self.dst_addr,
+                control: control.clone(),
+            };
+            if self.verdict_tx.send(verdict).is_err() {
+                // The runner is gone, the stream will never be serviced.
+                control.lock().close();
+            }
         }
 
-        if matches!(control.send_state, TcpSocketState::Normal) {
-            control.send_state = TcpSocketState::Close;
+        TcpStream {
+            src_addr: self.src_addr,
+            dst_addr: self.dst_addr,
+            syn_info: self.syn_info.take(),
+            config: self.config.clone(),
+            notify: self.notify.clone(),
+            dirty_sockets: self.dirty_sockets.clone(),
+            control,
+            lent_recv_buffer: SpinMutex::new(None),
         }
+    }
 
-        self.notify.notify_one();
+    /// Refuse the connection, no socket is ever created for it.
+    pub fn reject(mut self, reject: TcpReject) {
+        if let Some(handshake) = self.handshake.take() {
+            let _ = self.verdict_tx.send(TcpVerdict::Reject {
+                id: self.id,
+                handshake,
+                src_addr: self.src_addr,
+                dst_addr: self.dst_addr,
+                reject,
+            });
+        }
+    }
+}
+
+pub struct TcpStream {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    syn_info: Option<TcpSynInfo>,
+    config: Arc<TcpConfig>,
+    notify: SharedNotify,
+    dirty_sockets: DirtySockets,
+    control: SharedControl,
+    /// The receive buffer while [`AsyncBufRead::poll_fill_buf`] lends it out.
+    lent_recv_buffer: SpinMutex<Option<AdaptiveBuffer>>,
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let linger = self.control.lock().linger;
+        match linger {
+            Some(linger) if linger.is_zero() => self.abort(),
+            Some(linger) => {
+                let mut control = self.control.lock();
+                control.linger_deadline = Some(std::time::Instant::now() + linger);
+                self.wake_runner(&control);
+            }
+            None => {}
+        }
+
+        self.shutdown_read();
+        self.shutdown_write();
     }
 }
 
@@ -455,105 +1854,838 @@ impl TcpStream {
     pub fn remote_addr(&self) -> &SocketAddr {
         &self.dst_addr
     }
-}
 
-impl AsyncRead for TcpStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
+    /// Returns a snapshot of the connection state and statistics.
+    pub fn info(&self) -> TcpInfo {
+        let lent = self
+            .lent_recv_buffer
+            .lock()
+            .as_ref()
+            .map_or(0, AdaptiveBuffer::len);
+        let control = self.control.lock();
+        let send_queue = control.send_buffer.len() + control.socket_send_queue;
+        let recv_queue = control
+            .recv_buffer
+            .as_ref()
+            .map_or(lent, AdaptiveBuffer::len)
+            + control.socket_recv_queue;
+        let info = control
+            .stats
+            .lock()
+            .info(control.tcp_state, send_queue, recv_queue);
+        info
+    }
+
+    /// Returns the client's SYN, see [`PendingTcpStream::syn_info`]. `None` for
+    /// connections opened by a [`TcpConnector`].
+    pub fn syn_info(&self) -> Option<&TcpSynInfo> {
+        self.syn_info.as_ref()
+    }
+
+    /// Returns why the connection has been closed, `None` while the peer
+    /// hasn't closed it.
+    ///
+    /// [`TcpCloseReason::Fin`] is returned as soon as the peer has closed its
+    /// write half, even though this side may keep writing.
+    ///
+    /// Once a reset or timeout has been observed, reads and writes fail with
+    /// [`ConnectionReset`](std::io::ErrorKind::ConnectionReset),
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut) or
+    /// [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted) instead of
+    /// returning EOF or [`BrokenPipe`](std::io::ErrorKind::BrokenPipe).
+    pub fn close_reason(&self) -> Option<TcpCloseReason> {
+        self.control.lock().close_reason
+    }
+
+    /// Wait until the stream is readable, i.e. data has been received or the
+    /// read half is closed.
+    pub async fn readable(&self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_read_ready(cx)).await
+    }
+
+    /// Poll for read readiness, see [`Self::readable`].
+    pub fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.give_back_lent_recv_buffer();
+
+        let mut control = self.control.lock();
+
+        if control
+            .recv_buffer
+            .as_ref()
+            .is_some_and(|recv_buffer| !recv_buffer.is_empty())
+            || matches!(control.recv_state, TcpSocketState::Closed)
+        {
+            return Ok(()).into();
+        }
+
+        register_waker(&mut control.recv_waker, cx);
+        Poll::Pending
+    }
+
+    /// Wait until the stream is writable, i.e. there is room in the send buffer
+    /// or the write half is closed.
+    pub async fn writable(&self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_write_ready(cx)).await
+    }
+
+    /// Poll for write readiness, see [`Self::writable`].
+    pub fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut control = self.control.lock();
+
+        if !control.send_buffer.is_full()
+            || control.send_buffer.grow()
+            || !matches!(control.send_state, TcpSocketState::Normal)
+        {
+            return Ok(()).into();
+        }
+
+        register_waker(&mut control.send_waker, cx);
+        Poll::Pending
+    }
+
+    /// Read the received data without waiting, fails with
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock) if there is none.
+    ///
+    /// Returns `Ok(0)` once the peer has closed its write half.
+    pub fn try_read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read_buf = ReadBuf::new(buf);
+        match self.poll_read_priv(None, &mut read_buf) {
+            Poll::Ready(result) => result.map(|()| read_buf.filled().len()),
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Write data without waiting, fails with
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock) if the send buffer is full.
+    pub fn try_write(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.poll_write_priv(None, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Receive data without removing it from the receive buffer, waiting
+    /// until there is some.
+    ///
+    /// Returns `Ok(0)` once the peer has closed its write half.
+    pub async fn peek(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read_buf = ReadBuf::new(buf);
+        std::future::poll_fn(|cx| self.poll_peek(cx, &mut read_buf)).await
+    }
+
+    /// Poll to receive data without removing it from the receive buffer, see [`Self::peek`].
+    pub fn poll_peek(
+        &self,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
-    ) -> Poll<std::io::Result<()>> {
+    ) -> Poll<std::io::Result<usize>> {
+        self.give_back_lent_recv_buffer();
+
+        let Some(mut control) = ready!(self.poll_recv(Some(cx)))? else {
+            return Ok(0).into();
+        };
+        let recv_buffer = control.recv_buffer.as_mut().expect("checked by poll_recv");
+        let peek_buf = unsafe {
+            std::mem::transmute::<&mut [std::mem::MaybeUninit<u8>], &mut [u8]>(buf.unfilled_mut())
+        };
+        let n = recv_buffer.peek_slice(peek_buf);
+        buf.advance(n);
+        Ok(n).into()
+    }
+
+    /// Reset the connection, the peer gets a RST and anything not sent yet is lost.
+    ///
+    /// Reads and writes fail with [`ConnectionAborted`](std::io::ErrorKind::ConnectionAborted)
+    /// afterwards.
+    pub fn abort(&self) {
         let mut control = self.control.lock();
 
-        // Read from buffer
-        if control.recv_buffer.is_empty() {
-            // If socket is already closed / half closed, just return EOF directly.
-            if matches!(control.recv_state, TcpSocketState::Closed) {
-                return Ok(()).into();
-            }
+        // The runner aborts the socket, which sends the RST.
+        control.aborted = true;
+        control.set_close_reason(TcpCloseReason::Aborted);
+        control.close();
+        self.wake_runner(&control);
+    }
 
-            // Nothing could be read. Wait for notify.
-            if let Some(old_waker) = control.recv_waker.replace(cx.waker().clone()) {
-                if !old_waker.will_wake(cx.waker()) {
-                    old_waker.wake();
-                }
-            }
+    /// How dropping the stream closes the connection.
+    ///
+    /// With `None`, the default, the connection is closed gracefully in the
+    /// background. With `Some(duration)`, it is reset if the peer hasn't
+    /// acknowledged everything within `duration`, a zero duration resets it
+    /// right away.
+    pub fn set_linger(&self, linger: Option<std::time::Duration>) {
+        self.control.lock().linger = linger;
+    }
+
+    /// Returns the value set by [`Self::set_linger`].
+    pub fn linger(&self) -> Option<std::time::Duration> {
+        self.control.lock().linger
+    }
+
+    /// Override the [congestion control algorithm](TcpConfig::congestion_control)
+    /// of this connection.
+    pub fn set_congestion_control(&self, congestion_control: CongestionControl) {
+        self.set_socket_option(|control| control.congestion_control = congestion_control);
+    }
+
+    /// Returns the congestion control algorithm of this connection.
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.control.lock().congestion_control
+    }
+
+    /// Fail reads waiting longer than `timeout` for data with
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut), `None`, the default, waits
+    /// forever.
+    ///
+    /// The deadline is armed when a read starts waiting and cleared once a
+    /// read completes, however often it is polled meanwhile. A read dropped
+    /// while waiting, e.g. by `select!`, leaves its deadline to the next one,
+    /// as with `tokio-io-timeout`. Non-blocking reads like [`Self::try_read`]
+    /// never time out. Segments from the peer such as keep-alive ACKs don't
+    /// postpone the deadline, see [`Self::set_idle_timeout`] for a timeout
+    /// following the TCP activity.
+    pub fn set_read_timeout(&self, timeout: Option<std::time::Duration>) {
+        let mut control = self.control.lock();
+        control.read_timeout = timeout;
+        control.read_timer = None;
+    }
+
+    /// Returns the value set by [`Self::set_read_timeout`].
+    pub fn read_timeout(&self) -> Option<std::time::Duration> {
+        self.control.lock().read_timeout
+    }
+
+    /// Fail writes waiting longer than `timeout` for room in the send buffer,
+    /// and flushes waiting as long, with
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut). `None`, the default, waits
+    /// forever.
+    ///
+    /// Deadlines are kept by the stream, as with [`Self::set_read_timeout`].
+    pub fn set_write_timeout(&self, timeout: Option<std::time::Duration>) {
+        let mut control = self.control.lock();
+        control.write_timeout = timeout;
+        control.write_timer = None;
+    }
+
+    /// Returns the value set by [`Self::set_write_timeout`].
+    pub fn write_timeout(&self) -> Option<std::time::Duration> {
+        self.control.lock().write_timeout
+    }
+
+    /// Reset the connection when a read or write times out. Otherwise, the
+    /// default, the connection stays open and the operation may be retried.
+    pub fn set_reset_on_timeout(&self, reset: bool) {
+        self.control.lock().reset_on_timeout = reset;
+    }
+
+    /// Override the [idle timeout](TcpConfig::idle_timeout) of this connection.
+    ///
+    /// Any segment from the peer, keep-alive ACKs included, counts as activity.
+    /// Once it fires the connection is reset and reads and writes fail with
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut).
+    pub fn set_idle_timeout(&self, timeout: Option<std::time::Duration>) {
+        self.set_socket_option(|control| control.idle_timeout = timeout);
+    }
+
+    /// Returns the idle timeout of this connection.
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.control.lock().idle_timeout
+    }
+
+    /// Disable Nagle's algorithm, sending small segments right away instead
+    /// of coalescing them while data is unacknowledged.
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.set_socket_option(|control| control.nagle_enabled = !nodelay);
+    }
+
+    /// Returns whether Nagle's algorithm is disabled.
+    pub fn nodelay(&self) -> bool {
+        !self.control.lock().nagle_enabled
+    }
+
+    /// Override the [keep-alive interval](TcpConfig::keep_alive) of this
+    /// connection, `None` disables keep-alives.
+    ///
+    /// A keep-alive already scheduled is still sent, the new interval is in
+    /// effect from the next segment exchanged.
+    pub fn set_keepalive(&self, interval: Option<std::time::Duration>) {
+        self.set_socket_option(|control| control.keep_alive = interval);
+    }
+
+    /// Returns the keep-alive interval of this connection.
+    pub fn keepalive(&self) -> Option<std::time::Duration> {
+        self.control.lock().keep_alive
+    }
+
+    /// Override the [hop limit](TcpConfig::hop_limit) of this connection.
+    ///
+    /// A hop limit of zero is treated as `None`.
+    pub fn set_hop_limit(&self, hop_limit: Option<u8>) {
+        let hop_limit = hop_limit.filter(|&hop_limit| hop_limit != 0);
+        self.set_socket_option(|control| control.hop_limit = hop_limit);
+    }
+
+    /// Returns the hop limit of this connection, `None` for smoltcp's default.
+    pub fn hop_limit(&self) -> Option<u8> {
+        self.control.lock().hop_limit
+    }
+
+    /// Override the [ACK delay](TcpConfig::ack_delay) of this connection.
+    pub fn set_ack_delay(&self, delay: Option<std::time::Duration>) {
+        self.set_socket_option(|control| control.ack_delay = delay);
+    }
+
+    /// Returns the ACK delay of this connection.
+    pub fn ack_delay(&self) -> Option<std::time::Duration> {
+        self.control.lock().ack_delay
+    }
 
+    /// Record an option of the connection, the runner applies it to the
+    /// socket on its next loop, or once it has added it.
+    fn set_socket_option(&self, record: impl FnOnce(&mut TcpSocketControl)) {
+        let mut control = self.control.lock();
+        record(&mut control);
+        control.options_changed = true;
+        self.wake_runner(&control);
+    }
+
+    /// A read or write has timed out, reset the connection if asked to.
+    fn timed_out(&self, control: &mut TcpSocketControl) -> std::io::Error {
+        trace!(
+            "TCP stream timed out, {} <-> {}",
+            self.src_addr,
+            self.dst_addr
+        );
+        if control.reset_on_timeout {
+            control.aborted = true;
+            control.set_close_reason(TcpCloseReason::TimedOut);
+            control.close();
+            self.wake_runner(control);
+        }
+        std::io::ErrorKind::TimedOut.into()
+    }
+
+    /// Split the stream into a read half and a write half that can be moved
+    /// to different tasks.
+    ///
+    /// Dropping a half only shuts down its direction, the halves can be put
+    /// back together with [`OwnedReadHalf::reunite`].
+    pub fn into_split(mut self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let lent_recv_buffer = self.lent_recv_buffer.get_mut().take();
+        let inner = Arc::new(self);
+        (
+            OwnedReadHalf {
+                inner: Some(inner.clone()),
+                lent_recv_buffer,
+            },
+            OwnedWriteHalf { inner: Some(inner) },
+        )
+    }
+
+    /// Have the runner service the socket on its next loop.
+    fn wake_runner(&self, control: &TcpSocketControl) {
+        if let Some(handle) = control.socket {
+            self.dirty_sockets.lock().insert(handle);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Stop reading, the runner discards whatever is received from now on.
+    fn shutdown_read(&self) {
+        let mut control = self.control.lock();
+        if matches!(control.recv_state, TcpSocketState::Normal) {
+            control.recv_state = TcpSocketState::Close;
+            self.wake_runner(&control);
+        }
+    }
+
+    /// Stop writing, the runner sends a FIN once the send buffer is drained.
+    fn shutdown_write(&self) {
+        let mut control = self.control.lock();
+        if matches!(control.send_state, TcpSocketState::Normal) {
+            control.send_state = TcpSocketState::Close;
+            self.wake_runner(&control);
+        }
+    }
+
+    /// Wait until there is data to read, `cx` is `None` for a non-blocking read
+    /// which doesn't wait on the stream. Returns the locked control once the
+    /// receive buffer has data, `None` at EOF.
+    fn poll_recv(
+        &self,
+        cx: Option<&mut Context<'_>>,
+    ) -> Poll<std::io::Result<Option<SpinMutexGuard<'_, TcpSocketControl>>>> {
+        let mut control = self.control.lock();
+
+        if control
+            .recv_buffer
+            .as_ref()
+            .is_some_and(|recv_buffer| !recv_buffer.is_empty())
+        {
+            control.read_timer = None;
+            return Ok(Some(control)).into();
+        }
+
+        // If socket is already closed / half closed, just return EOF directly.
+        if matches!(control.recv_state, TcpSocketState::Closed) {
+            control.read_timer = None;
+            return match control.close_reason.and_then(TcpCloseReason::error) {
+                Some(err) => Err(err).into(),
+                None => Ok(None).into(),
+            };
+        }
+
+        let Some(cx) = cx else {
             return Poll::Pending;
+        };
+
+        let read_timeout = control.read_timeout;
+        if poll_timer(&mut control.read_timer, read_timeout, cx) {
+            return Err(self.timed_out(&mut control)).into();
         }
 
+        // Nothing could be read. Wait for notify.
+        register_waker(&mut control.recv_waker, cx);
+
+        Poll::Pending
+    }
+
+    /// Read into `buf`, `cx` is `None` for a non-blocking read which doesn't
+    /// wait on the stream.
+    fn poll_read_priv(
+        &self,
+        cx: Option<&mut Context<'_>>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.give_back_lent_recv_buffer();
+
+        let Some(mut control) = ready!(self.poll_recv(cx))? else {
+            return Ok(()).into();
+        };
+
+        // Read from the receive buffer
+        let recv_buffer = control.recv_buffer.as_mut().expect("checked by poll_recv");
+        let was_full = recv_buffer.is_full();
         let recv_buf = unsafe {
             std::mem::transmute::<&mut [std::mem::MaybeUninit<u8>], &mut [u8]>(buf.unfilled_mut())
         };
-        let n = control.recv_buffer.dequeue_slice(recv_buf);
+        let n = recv_buffer.dequeue_slice(recv_buf);
         buf.advance(n);
 
-        if n > 0 {
-            self.notify.notify_one();
+        // Let the runner move what the socket is holding back.
+        if was_full {
+            self.wake_runner(&control);
         }
-
         Ok(()).into()
     }
-}
 
-impl AsyncWrite for TcpStream {
-    fn poll_write(
-        self: Pin<&mut Self>,
+    /// Make `lent` hold the receive buffer with data for
+    /// [`AsyncBufRead::poll_fill_buf`] to serve, taking it from the runner once
+    /// there is some. `lent` is left `None` at EOF.
+    fn poll_lend_recv_buffer(
+        &self,
+        lent: &mut Option<AdaptiveBuffer>,
         cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if lent
+            .as_ref()
+            .is_some_and(|recv_buffer| !recv_buffer.is_empty())
+        {
+            return Ok(()).into();
+        }
+        if let Some(recv_buffer) = lent.take() {
+            self.give_back_recv_buffer(recv_buffer);
+        }
+
+        if let Some(mut control) = ready!(self.poll_recv(Some(cx)))? {
+            *lent = control.recv_buffer.take();
+        }
+        Ok(()).into()
+    }
+
+    /// Consume `amt` bytes of the lent receive buffer, giving it back to the
+    /// runner once it is drained.
+    fn consume_lent(&self, lent: &mut Option<AdaptiveBuffer>, amt: usize) {
+        let Some(ref mut recv_buffer) = lent else {
+            return;
+        };
+        recv_buffer.consume(amt);
+        if recv_buffer.is_empty() {
+            self.give_back_recv_buffer(lent.take().expect("checked above"));
+        }
+    }
+
+    /// Give the receive buffer back to the runner, which moves what the
+    /// socket has received meanwhile.
+    fn give_back_recv_buffer(&self, recv_buffer: AdaptiveBuffer) {
+        let mut control = self.control.lock();
+        control.recv_buffer = Some(recv_buffer);
+        if control.socket_recv_queue > 0 {
+            self.wake_runner(&control);
+        }
+    }
+
+    /// Give back the receive buffer lent to this stream's `poll_fill_buf`,
+    /// before reading from the runner's.
+    fn give_back_lent_recv_buffer(&self) {
+        let lent = self.lent_recv_buffer.lock().take();
+        if let Some(recv_buffer) = lent {
+            self.give_back_recv_buffer(recv_buffer);
+        }
+    }
+
+    fn poll_write_priv(
+        &self,
+        cx: Option<&mut Context<'_>>,
         buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_vectored_priv(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Fill the send buffer from the slices in order, stopping at the first one
+    /// that doesn't fit, and notify the runner once. `cx` is `None` for a
+    /// non-blocking write which doesn't wait on the stream.
+    fn poll_write_vectored_priv(
+        &self,
+        cx: Option<&mut Context<'_>>,
+        bufs: &[IoSlice<'_>],
     ) -> Poll<std::io::Result<usize>> {
         let mut control = self.control.lock();
 
         // If state == Close | Closing | Closed, the TCP stream WR half is closed.
         if !matches!(control.send_state, TcpSocketState::Normal) {
-            return Err(std::io::ErrorKind::BrokenPipe.into()).into();
+            control.write_timer = None;
+            return Err(control.closed_error()).into();
         }
 
-        // Write to buffer
-
-        if control.send_buffer.is_full() {
-            if let Some(old_waker) = control.send_waker.replace(cx.waker().clone()) {
-                if !old_waker.will_wake(cx.waker()) {
-                    old_waker.wake();
+        // Write to buffer, growing it while it keeps filling up.
+        if !control.send_buffer.is_full() || control.send_buffer.grow() {
+            let mut written = 0;
+            for buf in bufs {
+                let n = control.send_buffer.enqueue_slice(buf);
+                written += n;
+                if n < buf.len() {
+                    break;
                 }
             }
+            control.write_timer = None;
 
-            return Poll::Pending;
+            // Let the runner send the data.
+            self.wake_runner(&control);
+            return Ok(written).into();
         }
 
-        let n = control.send_buffer.enqueue_slice(buf);
+        let Some(cx) = cx else {
+            return Poll::Pending;
+        };
 
-        if n > 0 {
-            self.notify.notify_one();
+        let write_timeout = control.write_timeout;
+        if poll_timer(&mut control.write_timer, write_timeout, cx) {
+            return Err(self.timed_out(&mut control)).into();
         }
 
-        Ok(n).into()
+        register_waker(&mut control.send_waker, cx);
+
+        Poll::Pending
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        Ok(()).into()
+    fn poll_flush_priv(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut control = self.control.lock();
+
+        // Everything written has been handed to the socket, and acknowledged
+        // by the peer if asked to.
+        if control.send_buffer.is_empty()
+            && (!self.config.flush_waits_for_ack || control.socket_send_queue == 0)
+        {
+            control.write_timer = None;
+            return Ok(()).into();
+        }
+        if !matches!(control.send_state, TcpSocketState::Normal) {
+            control.write_timer = None;
+            return Err(control.closed_error()).into();
+        }
+
+        let write_timeout = control.write_timeout;
+        if poll_timer(&mut control.write_timer, write_timeout, cx) {
+            return Err(self.timed_out(&mut control)).into();
+        }
+
+        // The runner wakes the writer up whenever it has moved data to the
+        // socket, and on every ACK making room in the socket.
+        register_waker(&mut control.send_waker, cx);
+        Poll::Pending
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    fn poll_shutdown_priv(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         let mut control = self.control.lock();
 
+        // The FIN has been acknowledged, unless the connection has been
+        // reset, aborted or has timed out.
         if matches!(control.send_state, TcpSocketState::Closed) {
-            return Ok(()).into();
+            control.shutdown_timer = None;
+            return match control.close_reason.and_then(TcpCloseReason::error) {
+                Some(err) => Err(err).into(),
+                None => Ok(()).into(),
+            };
         }
 
         // SHUT_WR
         if matches!(control.send_state, TcpSocketState::Normal) {
             control.send_state = TcpSocketState::Close;
+            self.wake_runner(&control);
         }
 
-        if let Some(old_waker) = control.send_waker.replace(cx.waker().clone()) {
-            if !old_waker.will_wake(cx.waker()) {
-                old_waker.wake();
-            }
+        // The write half may have been closed already, e.g. by dropping an
+        // OwnedWriteHalf, the wait for the FIN's ACK is bounded all the same.
+        if control.shutdown_timer.is_none() {
+            control.shutdown_timer = self
+                .config
+                .shutdown_timeout
+                .map(|timeout| Box::pin(tokio::time::sleep(timeout)));
         }
 
-        self.notify.notify_one();
+        if let Some(ref mut timer) = control.shutdown_timer {
+            if timer.as_mut().poll(cx).is_ready() {
+                trace!(
+                    "TCP shutdown timed out, {} <-> {}",
+                    self.src_addr,
+                    self.dst_addr
+                );
+                control.shutdown_timer = None;
+                return Ok(()).into();
+            }
+        }
 
+        register_waker(&mut control.send_waker, cx);
         Poll::Pending
     }
 }
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.poll_read_priv(Some(cx), buf)
+    }
+}
+
+impl AsyncBufRead for TcpStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        // Taken out of the stream for the time it is lent to.
+        let mut lent = this.lent_recv_buffer.get_mut().take();
+        let result = this.poll_lend_recv_buffer(&mut lent, cx);
+        *this.lent_recv_buffer.get_mut() = lent;
+
+        ready!(result)?;
+        let lent = this.lent_recv_buffer.get_mut().as_ref();
+        Ok(lent.map_or(&[][..], AdaptiveBuffer::data)).into()
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        let mut lent = this.lent_recv_buffer.get_mut().take();
+        this.consume_lent(&mut lent, amt);
+        *this.lent_recv_buffer.get_mut() = lent;
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_priv(Some(cx), buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_vectored_priv(Some(cx), bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush_priv(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_shutdown_priv(cx)
+    }
+}
+
+/// The read half of a [`TcpStream`], created by [`TcpStream::into_split`].
+///
+/// Dropping it discards whatever the peer sends from then on.
+pub struct OwnedReadHalf {
+    inner: Option<Arc<TcpStream>>,
+    /// The receive buffer while [`AsyncBufRead::poll_fill_buf`] lends it out.
+    lent_recv_buffer: Option<AdaptiveBuffer>,
+}
+
+/// The write half of a [`TcpStream`], created by [`TcpStream::into_split`].
+///
+/// Dropping it sends a FIN once the written data has been sent.
+pub struct OwnedWriteHalf {
+    inner: Option<Arc<TcpStream>>,
+}
+
+/// Returned by [`OwnedReadHalf::reunite`] when the halves come from different streams.
+pub struct ReuniteError(pub OwnedReadHalf, pub OwnedWriteHalf);
+
+impl std::fmt::Debug for ReuniteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReuniteError(..)")
+    }
+}
+
+impl std::fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("tried to reunite halves that are not from the same stream")
+    }
+}
+
+impl std::error::Error for ReuniteError {}
+
+impl OwnedReadHalf {
+    pub fn local_addr(&self) -> &SocketAddr {
+        self.stream().local_addr()
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        self.stream().remote_addr()
+    }
+
+    /// Put the halves back together, fails if they come from different streams.
+    pub fn reunite(mut self, mut other: OwnedWriteHalf) -> Result<TcpStream, ReuniteError> {
+        if !Arc::ptr_eq(self.stream_arc(), other.stream_arc()) {
+            return Err(ReuniteError(self, other));
+        }
+
+        // The halves are dropped without shutting anything down.
+        other.inner.take();
+        let inner = self.inner.take().expect("stream taken by reunite");
+        let mut stream = Arc::try_unwrap(inner)
+            .ok()
+            .expect("TcpStream: try_unwrap failed in reunite");
+        *stream.lent_recv_buffer.get_mut() = self.lent_recv_buffer.take();
+        Ok(stream)
+    }
+
+    fn stream(&self) -> &TcpStream {
+        self.stream_arc()
+    }
+
+    fn stream_arc(&self) -> &Arc<TcpStream> {
+        self.inner.as_ref().expect("stream taken by reunite")
+    }
+}
+
+impl OwnedWriteHalf {
+    pub fn local_addr(&self) -> &SocketAddr {
+        self.stream().local_addr()
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        self.stream().remote_addr()
+    }
+
+    /// Put the halves back together, fails if they come from different streams.
+    pub fn reunite(self, other: OwnedReadHalf) -> Result<TcpStream, ReuniteError> {
+        other.reunite(self)
+    }
+
+    fn stream(&self) -> &TcpStream {
+        self.stream_arc()
+    }
+
+    fn stream_arc(&self) -> &Arc<TcpStream> {
+        self.inner.as_ref().expect("stream taken by reunite")
+    }
+}
+
+impl Drop for OwnedReadHalf {
+    fn drop(&mut self) {
+        if let Some(ref inner) = self.inner {
+            inner.shutdown_read();
+        }
+    }
+}
+
+impl Drop for OwnedWriteHalf {
+    fn drop(&mut self) {
+        if let Some(ref inner) = self.inner {
+            inner.shutdown_write();
+        }
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let stream = this.inner.as_ref().expect("stream taken by reunite");
+        if let Some(recv_buffer) = this.lent_recv_buffer.take() {
+            stream.give_back_recv_buffer(recv_buffer);
+        }
+        stream.poll_read_priv(Some(cx), buf)
+    }
+}
+
+impl AsyncBufRead for OwnedReadHalf {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let stream = this.inner.as_ref().expect("stream taken by reunite");
+        ready!(stream.poll_lend_recv_buffer(&mut this.lent_recv_buffer, cx))?;
+        let lent = this.lent_recv_buffer.as_ref();
+        Ok(lent.map_or(&[][..], AdaptiveBuffer::data)).into()
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        let stream = this.inner.as_ref().expect("stream taken by reunite");
+        stream.consume_lent(&mut this.lent_recv_buffer, amt);
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.stream().poll_write_priv(Some(cx), buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        self.stream().poll_write_vectored_priv(Some(cx), bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.stream().poll_flush_priv(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.stream().poll_shutdown_priv(cx)
+    }
+}
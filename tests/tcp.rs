@@ -0,0 +1,375 @@
+//! Drive the TCP stack with hand-built segments through the [`Stack`] sink and
+//! stream, as a TUN device would.
+
+use std::{
+    future::Future,
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use etherparse::{PacketBuilder, PacketBuilderStep, SlicedPacket, TcpHeader, TransportSlice};
+use futures::{SinkExt, StreamExt};
+use netstack_smoltcp::{Stack, StackBuilder, TcpCloseReason, TcpConfig, TcpState, TcpStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::oneshot,
+    time::timeout,
+};
+
+const PEER_ADDR: [u8; 4] = [10, 0, 0, 2];
+const PEER_PORT: u16 = 40000;
+const STACK_ADDR: [u8; 4] = [1, 2, 3, 4];
+const STACK_PORT: u16 = 80;
+const PEER_WINDOW: u16 = 0xFFFF;
+/// How long the peer waits for a segment before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A segment sent by the stack to the peer.
+struct Segment {
+    seq_number: u32,
+    syn: bool,
+    ack: bool,
+    fin: bool,
+    rst: bool,
+    payload: Vec<u8>,
+}
+
+/// The remote end of a single connection, tracking its sequence numbers.
+struct Peer {
+    stack: Stack,
+    seq_number: u32,
+    ack_number: u32,
+}
+
+impl Peer {
+    async fn send(
+        &mut self,
+        flags: impl FnOnce(PacketBuilderStep<TcpHeader>) -> PacketBuilderStep<TcpHeader>,
+        payload: &[u8],
+    ) {
+        let builder = flags(PacketBuilder::ipv4(PEER_ADDR, STACK_ADDR, 64).tcp(
+            PEER_PORT,
+            STACK_PORT,
+            self.seq_number,
+            PEER_WINDOW,
+        ));
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        self.stack.send(frame).await.unwrap();
+    }
+
+    async fn syn(&mut self) {
+        self.send(|builder| builder.syn(), &[]).await;
+        self.seq_number = self.seq_number.wrapping_add(1);
+    }
+
+    /// Acknowledge everything received in order so far.
+    async fn ack(&mut self) {
+        let ack_number = self.ack_number;
+        self.send(|builder| builder.ack(ack_number), &[]).await;
+    }
+
+    async fn data(&mut self, payload: &[u8]) {
+        let ack_number = self.ack_number;
+        self.send(|builder| builder.ack(ack_number).psh(), payload)
+            .await;
+        self.seq_number = self.seq_number.wrapping_add(payload.len() as u32);
+    }
+
+    async fn rst(&mut self) {
+        let ack_number = self.ack_number;
+        self.send(|builder| builder.rst().ack(ack_number), &[])
+            .await;
+    }
+
+    /// The next segment from the stack, panicking after [`RECV_TIMEOUT`].
+    async fn recv(&mut self) -> Segment {
+        let frame = timeout(RECV_TIMEOUT, self.stack.next())
+            .await
+            .expect("no segment from the stack")
+            .expect("stack closed")
+            .unwrap();
+        let packet = SlicedPacket::from_ip(&frame).unwrap();
+        let Some(TransportSlice::Tcp(tcp)) = packet.transport else {
+            panic!("not a TCP segment");
+        };
+        assert_eq!(
+            (tcp.source_port(), tcp.destination_port()),
+            (STACK_PORT, PEER_PORT)
+        );
+        let segment = Segment {
+            seq_number: tcp.sequence_number(),
+            syn: tcp.syn(),
+            ack: tcp.ack(),
+            fin: tcp.fin(),
+            rst: tcp.rst(),
+            payload: tcp.payload().to_vec(),
+        };
+        if segment.syn {
+            self.ack_number = segment.seq_number;
+        }
+        if segment.seq_number == self.ack_number {
+            let len = segment.payload.len() + segment.syn as usize + segment.fin as usize;
+            self.ack_number = self.ack_number.wrapping_add(len as u32);
+        }
+        segment
+    }
+
+    /// Receive segments up to the next reset.
+    async fn recv_rst(&mut self) -> Segment {
+        loop {
+            let segment = self.recv().await;
+            if segment.rst {
+                return segment;
+            }
+        }
+    }
+}
+
+/// Build a stack with `config`, and connect the peer to it.
+async fn connect(config: TcpConfig) -> (Peer, TcpStream) {
+    let (stack, runner, _, listener) = StackBuilder::default()
+        .enable_tcp(true)
+        .tcp_config(config)
+        .build()
+        .unwrap();
+    tokio::spawn(runner.unwrap());
+    let mut listener = listener.unwrap();
+    let (stream_tx, stream_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _, _) = listener.next().await.unwrap();
+        let _ = stream_tx.send(stream);
+        // Keep listening until the end of the test.
+        listener.next().await;
+    });
+
+    let mut peer = Peer {
+        stack,
+        seq_number: 1000,
+        ack_number: 0,
+    };
+    peer.syn().await;
+    let syn_ack = peer.recv().await;
+    assert!(syn_ack.syn && syn_ack.ack);
+    peer.ack().await;
+    let stream = bounded(stream_rx).await.unwrap();
+    // The stream is accepted on the SYN, wait for the ACK to be taken in.
+    bounded(async {
+        while stream.info().state != TcpState::Established {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await;
+    (peer, stream)
+}
+
+/// Await `future`, failing the test if it takes longer than [`RECV_TIMEOUT`].
+async fn bounded<F: Future>(future: F) -> F::Output {
+    timeout(RECV_TIMEOUT, future).await.expect("still pending")
+}
+
+fn assert_elapsed(start: Instant, min: Duration, max: Duration) {
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= min && elapsed < max,
+        "{elapsed:?} not within {min:?}..{max:?}"
+    );
+}
+
+#[tokio::test]
+async fn reset_by_peer() {
+    let (mut peer, mut stream) = connect(TcpConfig::default()).await;
+    peer.rst().await;
+
+    let mut buf = [0u8; 16];
+    let err = bounded(stream.read(&mut buf)).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(stream.close_reason(), Some(TcpCloseReason::Reset));
+}
+
+#[tokio::test]
+async fn idle_timeout() {
+    let config = TcpConfig::default().idle_timeout(Some(Duration::from_millis(200)));
+    let (_peer, mut stream) = connect(config).await;
+
+    // The peer stays silent.
+    let mut buf = [0u8; 16];
+    let err = bounded(stream.read(&mut buf)).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(stream.close_reason(), Some(TcpCloseReason::TimedOut));
+}
+
+#[tokio::test]
+async fn abort() {
+    let (mut peer, mut stream) = connect(TcpConfig::default()).await;
+    stream.abort();
+    peer.recv_rst().await;
+
+    let mut buf = [0u8; 16];
+    let err = bounded(stream.read(&mut buf)).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    let err = stream.write(b"ping").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    assert_eq!(stream.close_reason(), Some(TcpCloseReason::Aborted));
+}
+
+#[tokio::test]
+async fn flush_waits_for_send_buffer() {
+    let config = TcpConfig::default()
+        .socket_buffer_size(0x1000)
+        .min_buffer_size(0x10000);
+    let (mut peer, mut stream) = connect(config).await;
+
+    // Handed to the socket right away, no need for an ACK.
+    stream.write_all(b"ping").await.unwrap();
+    timeout(Duration::from_millis(100), stream.flush())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Only a socket buffer worth of it fits in the socket until the peer
+    // acknowledges some.
+    stream.write_all(&[7u8; 0x4000]).await.unwrap();
+    let mut flush = tokio::spawn(async move { stream.flush().await });
+    assert!(timeout(Duration::from_millis(200), &mut flush)
+        .await
+        .is_err());
+
+    let mut received = 0;
+    let res = loop {
+        tokio::select! {
+            res = &mut flush => break res.unwrap(),
+            segment = peer.recv() => {
+                received += segment.payload.len();
+                peer.ack().await;
+            }
+        }
+    };
+    res.unwrap();
+    assert!(received > 0x1000);
+}
+
+#[tokio::test]
+async fn flush_waits_for_ack() {
+    let config = TcpConfig::default().flush_waits_for_ack(true);
+    let (mut peer, mut stream) = connect(config).await;
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut flush = tokio::spawn(async move { stream.flush().await });
+    let segment = peer.recv().await;
+    assert_eq!(segment.payload, b"ping");
+    assert!(timeout(Duration::from_millis(200), &mut flush)
+        .await
+        .is_err());
+
+    peer.ack().await;
+    bounded(flush).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_fin_ack() {
+    let (mut peer, mut stream) = connect(TcpConfig::default()).await;
+
+    let mut shutdown = tokio::spawn(async move { stream.shutdown().await });
+    let segment = peer.recv().await;
+    assert!(segment.fin);
+    assert!(timeout(Duration::from_millis(200), &mut shutdown)
+        .await
+        .is_err());
+
+    peer.ack().await;
+    bounded(shutdown).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_timeout() {
+    let config = TcpConfig::default().shutdown_timeout(Some(Duration::from_millis(200)));
+    let (mut peer, mut stream) = connect(config).await;
+
+    // The FIN is never acknowledged.
+    let start = Instant::now();
+    bounded(stream.shutdown()).await.unwrap();
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+    assert!(peer.recv().await.fin);
+}
+
+#[tokio::test]
+async fn linger_zero_resets() {
+    let (mut peer, stream) = connect(TcpConfig::default()).await;
+    stream.set_linger(Some(Duration::ZERO));
+    drop(stream);
+
+    let segment = peer.recv().await;
+    assert!(segment.rst);
+}
+
+#[tokio::test]
+async fn linger_resets_unacknowledged() {
+    let (mut peer, mut stream) = connect(TcpConfig::default()).await;
+    stream.set_linger(Some(Duration::from_millis(200)));
+    stream.write_all(b"ping").await.unwrap();
+    stream.flush().await.unwrap();
+    assert_eq!(peer.recv().await.payload, b"ping");
+
+    // Neither the data nor the FIN are acknowledged.
+    let start = Instant::now();
+    drop(stream);
+    peer.recv_rst().await;
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn read_timeout() {
+    let (mut peer, mut stream) = connect(TcpConfig::default()).await;
+    stream.set_read_timeout(Some(Duration::from_millis(200)));
+
+    let mut buf = [0u8; 16];
+    let start = Instant::now();
+    let err = bounded(stream.read(&mut buf)).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+
+    // The deadline is kept across polls woken by something else.
+    let start = Instant::now();
+    let mut read = std::pin::pin!(stream.read(&mut buf));
+    let mut tick = tokio::time::interval(Duration::from_millis(10));
+    let err = bounded(async {
+        loop {
+            tokio::select! {
+                res = &mut read => break res.unwrap_err(),
+                _ = tick.tick() => {}
+            }
+        }
+    })
+    .await;
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+
+    // A timed out read doesn't close the connection.
+    peer.data(b"ping").await;
+    let n = bounded(stream.read(&mut buf)).await.unwrap();
+    assert_eq!(&buf[..n], b"ping");
+}
+
+#[tokio::test]
+async fn write_timeout() {
+    let config = TcpConfig::default()
+        .send_buffer_size(0x1000)
+        .socket_buffer_size(0x1000);
+    let (_peer, mut stream) = connect(config).await;
+    stream.set_write_timeout(Some(Duration::from_millis(200)));
+
+    // The peer never acknowledges anything.
+    let start = Instant::now();
+    let err = bounded(stream.write_all(&[7u8; 0x10000]))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+
+    // Neither is the send buffer flushed.
+    let start = Instant::now();
+    let err = bounded(stream.flush()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_elapsed(start, Duration::from_millis(190), Duration::from_secs(1));
+}